@@ -0,0 +1,165 @@
+use image::{AnimationDecoder, Rgba, RgbaImage};
+use std::fs::File;
+use std::io::Read;
+
+use crate::structs::Frame;
+
+//Browsers show frames with no delay for 100ms, and so do we
+const DEFAULT_DELAY: f32 = 0.1;
+
+//How much of the start of a file is read when looking for signs of animation
+const HEADER_SIZE: u64 = 64 * 1024;
+
+//Whether an APNG's acTL chunk comes before its image data, walking the chunks in the header
+fn png_has_actl(header: &[u8]) -> bool {
+    let mut offset = 8;
+    while offset + 8 <= header.len() {
+        let length = u32::from_be_bytes([header[offset], header[offset + 1], header[offset + 2], header[offset + 3]]) as usize;
+        match &header[offset + 4..offset + 8] {
+            b"acTL" => { return true; }
+            b"IDAT" => { return false; }
+            _ => { offset += 12 + length; }         //Length, type and CRC around the data
+        }
+    }
+    false
+}
+
+//Checks the file's header, so that only animations skip the thumbnail cache
+pub fn is_animated(path: &str) -> bool {
+    let mut header = Vec::new();
+    if let Err(e) = File::open(path).and_then(|f| f.take(HEADER_SIZE).read_to_end(&mut header)) {
+        println!("Error reading {}: {}", path, e);
+        return false;
+    }
+
+    if header.starts_with(b"GIF87a") || header.starts_with(b"GIF89a") {
+        true
+    } else if header.starts_with(b"\x89PNG\r\n\x1a\n") {
+        png_has_actl(&header)
+    } else if header.len() > 20 && header.starts_with(b"RIFF") && &header[8..16] == b"WEBPVP8X" {
+        header[20] & 0x02 != 0      //Animation flag of the extended format header
+    } else {
+        false
+    }
+}
+
+//Collects the frames of an animation that the image crate knows how to decode, such as APNG and animated WebP
+pub fn decode_frames<'a, D: AnimationDecoder<'a>>(decoder: D) -> Result<Vec<Frame>, String> {
+    let mut frames = Vec::new();
+    for frame in decoder.into_frames() {
+        let frame = frame.map_err(|e| e.to_string())?;
+        let (numerator, denominator) = frame.delay().numer_denom_ms();
+        let delay = match numerator {
+            0 => { DEFAULT_DELAY }
+            _ => { numerator as f32 / denominator as f32 / 1000.0 }
+        };
+        frames.push(Frame {
+            pixels: frame.into_buffer(),
+            delay
+        });
+    }
+
+    if frames.is_empty() {
+        return Err(String::from("animation has no frames"));
+    }
+    Ok(frames)
+}
+
+//Decodes every frame of a GIF, composited onto the full canvas the way a browser would show them
+pub fn decode_gif(bytes: &[u8]) -> Result<Vec<Frame>, String> {
+    let mut options = gif::DecodeOptions::new();
+    options.set_color_output(gif::ColorOutput::RGBA);
+    let mut decoder = options.read_info(bytes).map_err(|e| e.to_string())?;
+
+    let width = decoder.width() as u32;
+    let height = decoder.height() as u32;
+    let mut canvas = RgbaImage::new(width, height);
+    let mut frames = Vec::new();
+    while let Some(frame) = decoder.read_next_frame().map_err(|e| e.to_string())? {
+        //Only needed if this frame's disposal method asks for it to be undone
+        let previous = match frame.dispose {
+            gif::DisposalMethod::Previous => { Some(canvas.clone()) }
+            _ => { None }
+        };
+
+        //Frames are rectangles that get drawn over what's already there, with transparent pixels letting it show through
+        let (left, top) = (frame.left as u32, frame.top as u32);
+        for y in 0..frame.height as u32 {
+            for x in 0..frame.width as u32 {
+                let i = ((y * frame.width as u32 + x) * 4) as usize;
+                let pixel = &frame.buffer[i..i + 4];
+                if pixel[3] != 0 && left + x < width && top + y < height {
+                    canvas.put_pixel(left + x, top + y, Rgba([pixel[0], pixel[1], pixel[2], pixel[3]]));
+                }
+            }
+        }
+
+        let delay = match frame.delay {
+            0 => { DEFAULT_DELAY }
+            d => { d as f32 / 100.0 }       //GIF delays are in hundredths of a second
+        };
+        frames.push(Frame {
+            pixels: canvas.clone(),
+            delay
+        });
+
+        match frame.dispose {
+            gif::DisposalMethod::Background => {
+                for y in top..u32::min(top + frame.height as u32, height) {
+                    for x in left..u32::min(left + frame.width as u32, width) {
+                        canvas.put_pixel(x, y, Rgba([0, 0, 0, 0]));
+                    }
+                }
+            }
+            gif::DisposalMethod::Previous => {
+                if let Some(p) = previous {
+                    canvas = p;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    if frames.is_empty() {
+        return Err(String::from("GIF has no frames"));
+    }
+    Ok(frames)
+}
+
+//Index of the frame to show once an animation with these frame delays has been playing for time seconds
+pub fn frame_index(delays: &[f32], time: f32) -> usize {
+    let total: f32 = delays.iter().sum();
+    if delays.len() < 2 || total <= 0.0 {
+        return 0;
+    }
+
+    let mut t = time % total;
+    for (i, delay) in delays.iter().enumerate() {
+        if t < *delay {
+            return i;
+        }
+        t -= delay;
+    }
+    delays.len() - 1
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn frames_follow_their_delays_and_loop() {
+        let delays = [0.1, 0.2, 0.1];
+        assert_eq!(frame_index(&delays, 0.0), 0);
+        assert_eq!(frame_index(&delays, 0.15), 1);
+        assert_eq!(frame_index(&delays, 0.35), 2);
+        assert_eq!(frame_index(&delays, 0.45), 0);
+        assert_eq!(frame_index(&delays, 0.95), 1);
+    }
+
+    #[test]
+    fn still_images_stay_on_their_only_frame() {
+        assert_eq!(frame_index(&[0.0], 3.0), 0);
+        assert_eq!(frame_index(&[0.0, 0.0], 3.0), 0);
+    }
+}
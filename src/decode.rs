@@ -0,0 +1,174 @@
+use image::ImageFormat;
+use image::codecs::png::PngDecoder;
+use image::codecs::webp::WebPDecoder;
+use std::io::Cursor;
+use std::path::Path;
+
+use crate::animation;
+use crate::structs::Frame;
+
+//File extensions the loader can decode, without the dot
+//AVIF is only among them when built with the avif feature
+#[cfg(feature = "avif")]
+pub const SUPPORTED_EXTENSIONS: [&str; 10] = ["png", "apng", "jpg", "jpeg", "gif", "webp", "avif", "bmp", "tif", "tiff"];
+#[cfg(not(feature = "avif"))]
+pub const SUPPORTED_EXTENSIONS: [&str; 9] = ["png", "apng", "jpg", "jpeg", "gif", "webp", "bmp", "tif", "tiff"];
+
+//Whether a file looks like something the loader can decode, going by its extension
+pub fn is_supported_path(path: &Path) -> bool {
+    match path.extension().and_then(|e| e.to_str()) {
+        Some(ext) => { SUPPORTED_EXTENSIONS.contains(&ext.to_lowercase().as_str()) }
+        None => { false }
+    }
+}
+
+//The "*.ext" patterns for the open file dialog
+pub fn dialog_filter() -> Vec<String> {
+    SUPPORTED_EXTENSIONS.iter().map(|ext| format!("*.{}", ext)).collect()
+}
+
+//image::guess_format only recognizes AVIF files whose ftyp box is one of two sizes, so the brand is checked here first
+//This happens even without the avif feature, so that those files fail with a clear "unsupported format" message
+fn guess_format(bytes: &[u8]) -> Result<ImageFormat, String> {
+    if bytes.len() >= 12 && &bytes[4..8] == b"ftyp" && (&bytes[8..12] == b"avif" || &bytes[8..12] == b"avis") {
+        return Ok(ImageFormat::Avif);
+    }
+    image::guess_format(bytes).map_err(|e| e.to_string())
+}
+
+fn still(bytes: &[u8], format: ImageFormat) -> Result<Vec<Frame>, String> {
+    let pixels = image::load_from_memory_with_format(bytes, format).map_err(|e| e.to_string())?.to_rgba8();
+    Ok(vec![Frame { pixels, delay: 0.0 }])
+}
+
+//Decodes a file's contents into its frames, sniffing the format from the data rather than trusting the extension
+//Formats that can be animated come back with every frame, everything else with exactly one
+pub fn decode(bytes: &[u8]) -> Result<(ImageFormat, Vec<Frame>), String> {
+    let format = guess_format(bytes)?;
+    let frames = match format {
+        ImageFormat::Gif => { animation::decode_gif(bytes)? }
+        ImageFormat::Png => {
+            let decoder = PngDecoder::new(Cursor::new(bytes)).map_err(|e| e.to_string())?;
+            match decoder.is_apng() {
+                true => { animation::decode_frames(decoder.apng())? }
+                false => { still(bytes, format)? }
+            }
+        }
+        ImageFormat::WebP => {
+            let decoder = WebPDecoder::new(Cursor::new(bytes)).map_err(|e| e.to_string())?;
+            match decoder.has_animation() {
+                true => { animation::decode_frames(decoder)? }
+                false => { still(bytes, format)? }
+            }
+        }
+        ImageFormat::Jpeg | ImageFormat::Bmp | ImageFormat::Tiff => { still(bytes, format)? }
+        #[cfg(feature = "avif")]
+        ImageFormat::Avif => { still(bytes, format)? }
+        _ => { return Err(format!("unsupported format {:?}", format)); }
+    };
+    Ok((format, frames))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::Rgba;
+    use std::fs;
+
+    fn fixture(name: &str) -> (ImageFormat, Vec<Frame>) {
+        let bytes = fs::read(format!("{}/tests/fixtures/{}", env!("CARGO_MANIFEST_DIR"), name)).unwrap();
+        decode(&bytes).unwrap()
+    }
+
+    #[test]
+    fn bmp() {
+        let (format, frames) = fixture("still.bmp");
+        assert_eq!(format, ImageFormat::Bmp);
+        assert_eq!(frames.len(), 1);
+        assert_eq!(frames[0].pixels.dimensions(), (3, 2));
+        assert_eq!(*frames[0].pixels.get_pixel(0, 0), Rgba([255, 0, 0, 255]));
+    }
+
+    #[test]
+    fn tiff() {
+        let (format, frames) = fixture("still.tiff");
+        assert_eq!(format, ImageFormat::Tiff);
+        assert_eq!(frames.len(), 1);
+        assert_eq!(frames[0].pixels.dimensions(), (3, 2));
+        assert_eq!(*frames[0].pixels.get_pixel(2, 1), Rgba([0, 255, 0, 255]));
+    }
+
+    #[test]
+    fn gif() {
+        let (format, frames) = fixture("animated.gif");
+        assert_eq!(format, ImageFormat::Gif);
+        assert_eq!(frames.len(), 3);
+        assert!(frames.iter().all(|f| f.pixels.dimensions() == (3, 2)));
+        assert_eq!(frames.iter().map(|f| f.delay).collect::<Vec<f32>>(), [0.1, 0.2, 0.1]);
+
+        //The second frame only covers one pixel and is drawn over the first
+        assert_eq!(*frames[0].pixels.get_pixel(1, 1), Rgba([255, 0, 0, 255]));
+        assert_eq!(*frames[1].pixels.get_pixel(1, 1), Rgba([0, 0, 255, 255]));
+        assert_eq!(*frames[1].pixels.get_pixel(0, 0), Rgba([255, 0, 0, 255]));
+
+        //It's cleared to the background afterwards, and the third frame's transparent pixel lets the first show through
+        assert_eq!(*frames[2].pixels.get_pixel(1, 1), Rgba([0, 0, 0, 0]));
+        assert_eq!(*frames[2].pixels.get_pixel(0, 0), Rgba([0, 255, 0, 255]));
+        assert_eq!(*frames[2].pixels.get_pixel(1, 0), Rgba([255, 0, 0, 255]));
+    }
+
+    #[test]
+    fn webp() {
+        let (format, frames) = fixture("still.webp");
+        assert_eq!(format, ImageFormat::WebP);
+        assert_eq!(frames.len(), 1);
+        assert_eq!(frames[0].pixels.dimensions(), (2, 2));
+        assert_eq!(*frames[0].pixels.get_pixel(1, 1), Rgba([255, 255, 0, 255]));
+    }
+
+    #[test]
+    fn animated_webp() {
+        let (format, frames) = fixture("animated.webp");
+        assert_eq!(format, ImageFormat::WebP);
+        assert_eq!(frames.len(), 3);
+        assert_eq!(*frames[1].pixels.get_pixel(0, 0), Rgba([0, 255, 0, 255]));
+        assert!(frames.iter().all(|f| (f.delay - 0.1).abs() < 0.001));
+    }
+
+    #[test]
+    fn apng() {
+        let (format, frames) = fixture("animated.png");
+        assert_eq!(format, ImageFormat::Png);
+        assert_eq!(frames.len(), 2);
+        assert_eq!(*frames[0].pixels.get_pixel(0, 0), Rgba([255, 0, 0, 255]));
+        assert_eq!(*frames[1].pixels.get_pixel(0, 0), Rgba([0, 0, 255, 255]));
+        assert!(frames.iter().all(|f| (f.delay - 0.1).abs() < 0.001));
+    }
+
+    #[test]
+    #[cfg(feature = "avif")]
+    fn avif() {
+        let (format, frames) = fixture("still.avif");
+        assert_eq!(format, ImageFormat::Avif);
+        assert_eq!(frames.len(), 1);
+        assert_eq!(frames[0].pixels.dimensions(), (4, 4));
+    }
+
+    #[test]
+    fn animation_is_detected_from_the_header() {
+        let path = |name| format!("{}/tests/fixtures/{}", env!("CARGO_MANIFEST_DIR"), name);
+        assert!(animation::is_animated(&path("animated.gif")));
+        assert!(animation::is_animated(&path("animated.png")));
+        assert!(animation::is_animated(&path("animated.webp")));
+        assert!(!animation::is_animated(&path("still.webp")));
+        assert!(!animation::is_animated(&path("still.bmp")));
+    }
+
+    #[test]
+    fn extensions() {
+        assert!(is_supported_path(Path::new("a/b.WEBP")));
+        assert_eq!(is_supported_path(Path::new("c.avif")), cfg!(feature = "avif"));
+        assert!(!is_supported_path(Path::new("d.txt")));
+        assert!(!is_supported_path(Path::new("folder")));
+    }
+}
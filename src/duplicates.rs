@@ -0,0 +1,61 @@
+use image::RgbaImage;
+
+use crate::library::ImageFingerprint;
+
+//64-bit difference hash: shrink to 9x8 greyscale and record whether each pixel is brighter than its right neighbour
+//Re-saved, recompressed and resized copies of a picture land within a few bits of each other
+pub fn dhash(image: &RgbaImage) -> u64 {
+    let small = image::imageops::thumbnail(image, 9, 8);
+    let luma = |x, y| {
+        let p = small.get_pixel(x, y);
+        0.299 * p[0] as f32 + 0.587 * p[1] as f32 + 0.114 * p[2] as f32
+    };
+
+    let mut hash = 0;
+    for y in 0..8 {
+        for x in 0..8 {
+            hash <<= 1;
+            if luma(x, y) < luma(x + 1, y) {
+                hash |= 1;
+            }
+        }
+    }
+    hash
+}
+
+pub fn hamming_distance(a: u64, b: u64) -> u32 {
+    (a ^ b).count_ones()
+}
+
+fn find_root(parents: &mut [usize], i: usize) -> usize {
+    let mut root = i;
+    while parents[root] != root {
+        root = parents[root];
+    }
+    parents[i] = root;
+    root
+}
+
+//Groups images whose perceptual hashes are within max_distance bits of each other, transitively
+//Only groups with more than one member are returned
+pub fn group_similar(images: Vec<ImageFingerprint>, max_distance: u32) -> Vec<Vec<ImageFingerprint>> {
+    //Union-find over every pair
+    let mut parents: Vec<usize> = (0..images.len()).collect();
+    for i in 0..images.len() {
+        for j in (i + 1)..images.len() {
+            if hamming_distance(images[i].phash, images[j].phash) <= max_distance {
+                let a = find_root(&mut parents, i);
+                let b = find_root(&mut parents, j);
+                parents[a] = b;
+            }
+        }
+    }
+
+    let mut groups: Vec<Vec<ImageFingerprint>> = (0..images.len()).map(|_| Vec::new()).collect();
+    for (i, image) in images.into_iter().enumerate() {
+        let root = find_root(&mut parents, i);
+        groups[root].push(image);
+    }
+    groups.retain(|g| g.len() > 1);
+    groups
+}
@@ -0,0 +1,16 @@
+use sha2::{Digest, Sha256};
+use std::fs::File;
+use std::io;
+
+//Lowercase hex SHA-256 of the file's contents. This is what identifies an image in the database
+pub fn sha256_file(path: &str) -> io::Result<String> {
+    let mut file = File::open(path)?;
+    let mut hasher = Sha256::new();
+    io::copy(&mut file, &mut hasher)?;
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+//Same as sha256_file() for a file that has already been read into memory
+pub fn sha256_bytes(bytes: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(bytes))
+}
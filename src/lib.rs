@@ -0,0 +1,4 @@
+//The database side of uwu_db, kept apart from the GUI so that it can be reused and tested on its own
+pub mod library;
+pub mod migrations;
+pub mod query;
@@ -0,0 +1,1392 @@
+use sqlite::State;
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::path::Path;
+
+use crate::migrations;
+use crate::query;
+use crate::migrations::MigrationReport;
+
+//Reasons a database file can fail to open as a library
+pub enum OpenError {
+    Sqlite(sqlite::Error),
+    NotALibrary,                        //The file is not a uwu_db library, or not a database at all
+    NewerVersion(i64),                  //The library was written by a newer build with this schema version
+    Migration(i64, sqlite::Error)       //Upgrading to this schema version failed and was rolled back
+}
+
+impl From<sqlite::Error> for OpenError {
+    fn from(e: sqlite::Error) -> Self {
+        //SQLITE_NOTADB
+        if e.code == Some(26) {
+            OpenError::NotALibrary
+        } else {
+            OpenError::Sqlite(e)
+        }
+    }
+}
+
+impl fmt::Display for OpenError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            OpenError::Sqlite(e) => { write!(f, "{}", e) }
+            OpenError::NotALibrary => { write!(f, "This file is not a uwu_db library.") }
+            OpenError::NewerVersion(v) => {
+                write!(f, "This library uses schema version {}, but this build only understands up to version {}.\nPlease open it with a newer build of uwu_db.", v, migrations::current_version())
+            }
+            OpenError::Migration(v, e) => { write!(f, "Upgrading the library to schema version {} failed: {}\nNo changes were made.", v, e) }
+        }
+    }
+}
+
+//Reasons a relation between two tags can be refused
+pub enum TagRelationError {
+    Sqlite(sqlite::Error),
+    SameTag,                            //A tag can't imply or be the parent of itself
+    Cycle(Vec<String>)                  //The relation would close this loop of tags, which starts and ends with the same one
+}
+
+impl From<sqlite::Error> for TagRelationError {
+    fn from(e: sqlite::Error) -> Self {
+        TagRelationError::Sqlite(e)
+    }
+}
+
+impl fmt::Display for TagRelationError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TagRelationError::Sqlite(e) => { write!(f, "{}", e) }
+            TagRelationError::SameTag => { write!(f, "A tag can't be related to itself.") }
+            TagRelationError::Cycle(path) => { write!(f, "This would make a loop of tags:\n{}", path.join(" -> ")) }
+        }
+    }
+}
+
+//Reasons a name can't be given to a tag or an alias, as tag names and alias names must never overlap
+pub enum TagNameError {
+    Sqlite(sqlite::Error),
+    TagExists(String),                  //There's already a tag with this name
+    AliasExists(String, String)         //The name is already an alias of this tag
+}
+
+impl From<sqlite::Error> for TagNameError {
+    fn from(e: sqlite::Error) -> Self {
+        TagNameError::Sqlite(e)
+    }
+}
+
+impl fmt::Display for TagNameError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TagNameError::Sqlite(e) => { write!(f, "{}", e) }
+            TagNameError::TagExists(name) => { write!(f, "There's already a tag called \"{}\".\nMerge the tags instead.", name) }
+            TagNameError::AliasExists(name, tag) => { write!(f, "\"{}\" is an alias of \"{}\".\nRemove the alias first.", name, tag) }
+        }
+    }
+}
+
+//The identifying hashes of one image
+pub struct ImageFingerprint {
+    pub hash: String,
+    pub name: String,
+    pub phash: u64
+}
+
+//An image as returned by a query
+pub struct ImageRow {
+    pub name: String,                   //Filename in the image directory
+    pub hash: Option<String>,           //None if the image hasn't been hashed yet
+    pub metadata: Option<ImageMetadata> //None if it hasn't been recorded yet
+}
+
+//Images with tag also count as having implied
+pub struct Implication {
+    pub tag: String,
+    pub implied: String,
+    pub materialized: bool              //Whether implied is actually written to images when tag is applied, or only found by searches
+}
+
+//Facts about an image file that can be known without decoding it again
+#[derive(Clone)]
+pub struct ImageMetadata {
+    pub width: u32,
+    pub height: u32,
+    pub size: u64,                      //File size in bytes
+    pub format: String,                 //Lowercase format name e.g. "png"
+    pub modified: Option<i64>           //File modification time as a unix timestamp
+}
+
+//What the gallery can be sorted by
+#[derive(Clone, Copy, PartialEq)]
+pub enum SortKey {
+    Filename,
+    Imported,
+    Modified,
+    Dimensions,
+    Size,
+    TagCount,
+    Random
+}
+
+impl SortKey {
+    pub const ALL: [SortKey; 7] = [SortKey::Filename, SortKey::Imported, SortKey::Modified, SortKey::Dimensions, SortKey::Size, SortKey::TagCount, SortKey::Random];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            SortKey::Filename => { "Filename" }
+            SortKey::Imported => { "Import date" }
+            SortKey::Modified => { "File date" }
+            SortKey::Dimensions => { "Dimensions" }
+            SortKey::Size => { "File size" }
+            SortKey::TagCount => { "Tag count" }
+            SortKey::Random => { "Random" }
+        }
+    }
+}
+
+pub struct SortOrder {
+    pub key: SortKey,
+    pub descending: bool,
+    pub seed: i32                       //Seed for SortKey::Random. The same seed always gives the same shuffle
+}
+
+impl SortOrder {
+    //ORDER BY clause contents. Ties are broken by id so that the order is always fully determined
+    fn to_sql(&self) -> String {
+        let expression = match self.key {
+            SortKey::Filename => { String::from("images.path COLLATE NOCASE") }
+            SortKey::Imported => { String::from("images.imported") }
+            SortKey::Modified => { String::from("images.modified") }
+            SortKey::Dimensions => { String::from("images.width * images.height") }
+            SortKey::Size => { String::from("images.size") }
+            SortKey::TagCount => { String::from("(SELECT COUNT(*) FROM image_tags WHERE image_tags.image_id=images.id)") }
+
+            //sqlite's random() can't be seeded, so shuffle with a hash of the id instead
+            //The seed is XORed in between two multiplications, as only adding it would rotate the same cycle every time
+            //sqlite has no XOR operator, so a ^ b is written as (a | b) - (a & b)
+            SortKey::Random => {
+                let seed = (self.seed as u32).wrapping_mul(2654435761);
+                let id = "((images.id * 1103515245) % 4294967296)";
+                format!("((({} | {}) - ({} & {})) * 1597334677) % 4294967296", id, seed, id, seed)
+            }
+        };
+        let direction = if self.descending { "DESC" } else { "ASC" };
+        format!("{} {}, images.id {}", expression, direction, direction)
+    }
+}
+
+//Typed wrapper around the sqlite connection to an images.db file
+//Every query in here uses bound parameters, so tag names and filenames can contain whatever characters they like
+pub struct Library {
+    connection: sqlite::Connection,
+    pub migration: MigrationReport          //What was done to the schema when this library was opened
+}
+
+impl Library {
+    //Opens the database at path, creating or upgrading the schema as needed
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, OpenError> {
+        let connection = sqlite::open(path)?;
+        let migration = migrations::migrate(&connection)?;
+        Ok(Library {
+            connection,
+            migration
+        })
+    }
+
+    //Opens a library that only lives in memory, which is what the tests run against
+    pub fn open_in_memory() -> Result<Self, OpenError> {
+        Self::open(":memory:")
+    }
+
+    //Runs f inside of a transaction, rolling back if it returns an error
+    pub fn transaction<T, E: From<sqlite::Error>, F: FnOnce(&Self) -> Result<T, E>>(&self, f: F) -> Result<T, E> {
+        self.connection.execute("BEGIN;")?;
+        match f(self) {
+            Ok(v) => {
+                self.connection.execute("COMMIT;")?;
+                Ok(v)
+            }
+            Err(e) => {
+                if let Err(rollback_e) = self.connection.execute("ROLLBACK;") {
+                    println!("Error rolling back transaction: {}", rollback_e);
+                }
+                Err(e)
+            }
+        }
+    }
+
+    //Inserts an image with the given content hash, stored in the image directory as name
+    pub fn add_image(&self, name: &str, hash: &str) -> sqlite::Result<()> {
+        let mut statement = self.connection.prepare("INSERT OR IGNORE INTO images (path, hash, imported) VALUES (?, ?, strftime('%s', 'now'));")?;
+        statement.bind(1, name)?;
+        statement.bind(2, hash)?;
+        run(&mut statement)
+    }
+
+    //The name the image with this hash is stored under, if there is one
+    pub fn image_name_for_hash(&self, hash: &str) -> sqlite::Result<Option<String>> {
+        let mut statement = self.connection.prepare("SELECT path FROM images WHERE hash=?;")?;
+        statement.bind(1, hash)?;
+        Ok(read_strings(&mut statement)?.pop())
+    }
+
+    //Looks up the row stored under name
+    //Returns None if there is no such row and Some(None) if the row hasn't been hashed yet
+    pub fn image_hash_for_name(&self, name: &str) -> sqlite::Result<Option<Option<String>>> {
+        let mut statement = self.connection.prepare("SELECT hash FROM images WHERE path=?;")?;
+        statement.bind(1, name)?;
+        match statement.next()? {
+            State::Row => {
+                match statement.read::<sqlite::Value>(0)? {
+                    sqlite::Value::String(h) => { Ok(Some(Some(h))) }
+                    _ => { Ok(Some(None)) }
+                }
+            }
+            State::Done => { Ok(None) }
+        }
+    }
+
+    //Makes sure the image is in the database under name, hashing the row if it predates hashing
+    pub fn register_image(&self, name: &str, hash: &str) -> sqlite::Result<()> {
+        match self.image_hash_for_name(name)? {
+            Some(Some(_)) => { Ok(()) }
+            Some(None) => { self.set_image_hash(name, hash) }
+            None => { self.add_image(name, hash) }
+        }
+    }
+
+    //Folds a row from before hashing into the row that already has its hash, for when the two turn out to be the same image
+    //The row's tags are carried over before it's deleted. Returns whether that happened
+    pub fn merge_unhashed_duplicate(&self, name: &str, hash: &str) -> sqlite::Result<bool> {
+        self.transaction(|lib| {
+            match (lib.image_hash_for_name(name)?, lib.image_name_for_hash(hash)?) {
+                (Some(None), Some(existing)) if existing != name => {
+                    let mut statement = lib.connection.prepare("
+                        INSERT OR IGNORE INTO image_tags (image_id, tag_id)
+                            SELECT (SELECT id FROM images WHERE hash=?), tag_id FROM image_tags
+                            WHERE image_id=(SELECT id FROM images WHERE path=?);
+                    ")?;
+                    statement.bind(1, hash)?;
+                    statement.bind(2, name)?;
+                    run(&mut statement)?;
+                    lib.delete_image_named(name)?;
+                    Ok(true)
+                }
+                _ => { Ok(false) }
+            }
+        })
+    }
+
+    //Fills in the hash of a row from before images were hashed
+    pub fn set_image_hash(&self, name: &str, hash: &str) -> sqlite::Result<()> {
+        let mut statement = self.connection.prepare("UPDATE images SET hash=? WHERE path=?;")?;
+        statement.bind(1, hash)?;
+        statement.bind(2, name)?;
+        run(&mut statement)
+    }
+
+    //Stores the intrinsic metadata of an image
+    //Rows that predate import timestamps are stamped with the current time
+    pub fn set_image_metadata(&self, hash: &str, metadata: &ImageMetadata) -> sqlite::Result<()> {
+        let mut statement = self.connection.prepare("
+            UPDATE images SET width=?, height=?, size=?, format=?, modified=?, imported=COALESCE(imported, strftime('%s', 'now'))
+            WHERE hash=?;
+        ")?;
+        statement.bind(1, metadata.width as i64)?;
+        statement.bind(2, metadata.height as i64)?;
+        statement.bind(3, metadata.size as i64)?;
+        statement.bind(4, &*metadata.format)?;
+        match metadata.modified {
+            Some(t) => { statement.bind(5, t)?; }
+            None => { statement.bind(5, ())?; }
+        }
+        statement.bind(6, hash)?;
+        run(&mut statement)
+    }
+
+    //The stored metadata of an image, if it has been recorded
+    pub fn image_metadata(&self, hash: &str) -> sqlite::Result<Option<ImageMetadata>> {
+        let mut statement = self.connection.prepare("SELECT width, height, size, format, modified FROM images WHERE hash=?;")?;
+        statement.bind(1, hash)?;
+        match statement.next()? {
+            State::Row => { read_metadata(&statement, 0) }
+            State::Done => { Ok(None) }
+        }
+    }
+
+    //Stores the perceptual hash of an image. The bits are stored as a signed integer as that's all sqlite has
+    pub fn set_image_phash(&self, hash: &str, phash: u64) -> sqlite::Result<()> {
+        let mut statement = self.connection.prepare("UPDATE images SET phash=? WHERE hash=?;")?;
+        statement.bind(1, phash as i64)?;
+        statement.bind(2, hash)?;
+        run(&mut statement)
+    }
+
+    //Every image that has had its perceptual hash computed
+    pub fn image_fingerprints(&self) -> sqlite::Result<Vec<ImageFingerprint>> {
+        let mut statement = self.connection.prepare("SELECT hash, path, phash FROM images WHERE hash NOT NULL AND phash NOT NULL ORDER BY path;")?;
+        let mut fingerprints = Vec::new();
+        while let State::Row = statement.next()? {
+            fingerprints.push(ImageFingerprint {
+                hash: statement.read::<String>(0)?,
+                name: statement.read::<String>(1)?,
+                phash: statement.read::<i64>(2)? as u64
+            });
+        }
+        Ok(fingerprints)
+    }
+
+    //Applies the union of the tags of every image in others to keep, then deletes the others' rows if delete is set
+    //This is all one transaction so that an image is never deleted without its tags having been carried over
+    pub fn merge_duplicates(&self, keep: &str, others: &[&str], delete: bool) -> sqlite::Result<()> {
+        self.transaction(|lib| {
+            for other in others {
+                let mut statement = lib.connection.prepare("
+                    INSERT OR IGNORE INTO image_tags (image_id, tag_id)
+                        SELECT (SELECT id FROM images WHERE hash=?), tag_id FROM image_tags
+                        WHERE image_id=(SELECT id FROM images WHERE hash=?);
+                ")?;
+                statement.bind(1, keep)?;
+                statement.bind(2, *other)?;
+                run(&mut statement)?;
+
+                if delete {
+                    lib.delete_image(other)?;
+                }
+            }
+            Ok(())
+        })
+    }
+
+    //Deletes an image. Its tag relationships go with it via ON DELETE CASCADE
+    pub fn delete_image(&self, hash: &str) -> sqlite::Result<()> {
+        let mut statement = self.connection.prepare("DELETE FROM images WHERE hash=?;")?;
+        statement.bind(1, hash)?;
+        run(&mut statement)
+    }
+
+    //Deletes the row for a file that's gone, which may never have been hashed
+    pub fn delete_image_named(&self, name: &str) -> sqlite::Result<()> {
+        let mut statement = self.connection.prepare("DELETE FROM images WHERE path=?;")?;
+        statement.bind(1, name)?;
+        run(&mut statement)
+    }
+
+    //Every tag in the database in alphabetical order
+    pub fn all_tags(&self) -> sqlite::Result<Vec<String>> {
+        let mut statement = self.connection.prepare("SELECT name FROM tags ORDER BY name;")?;
+        read_strings(&mut statement)
+    }
+
+    //The tag that name stands for if it's an alias, otherwise name itself
+    pub fn resolve_tag(&self, name: &str) -> sqlite::Result<String> {
+        let mut statement = self.connection.prepare("SELECT tags.name FROM tag_aliases JOIN tags ON tags.id=tag_aliases.tag_id WHERE alias=?;")?;
+        statement.bind(1, name)?;
+        match read_strings(&mut statement)?.pop() {
+            Some(tag) => { Ok(tag) }
+            None => { Ok(String::from(name)) }
+        }
+    }
+
+    //Inserts a tag into the database if it doesn't already exist
+    //An alias isn't created as a tag of its own. Returns the name of the tag that ends up being used
+    pub fn create_tag(&self, tag: &str) -> sqlite::Result<String> {
+        let tag = self.resolve_tag(tag)?;
+        let mut statement = self.connection.prepare("INSERT OR IGNORE INTO tags (name) VALUES (?);")?;
+        statement.bind(1, &*tag)?;
+        run(&mut statement)?;
+        Ok(tag)
+    }
+
+    //Every alias along with the tag it stands for, ordered by alias
+    pub fn aliases(&self) -> sqlite::Result<Vec<(String, String)>> {
+        let mut statement = self.connection.prepare("SELECT alias, tags.name FROM tag_aliases JOIN tags ON tags.id=tag_aliases.tag_id ORDER BY alias;")?;
+        let mut aliases = Vec::new();
+        while let State::Row = statement.next()? {
+            aliases.push((statement.read::<String>(0)?, statement.read::<String>(1)?));
+        }
+        Ok(aliases)
+    }
+
+    //Makes alias stand for tag, replacing whatever it stood for before
+    //Refused if alias is the name of a tag, as that tag would become unreachable by name
+    pub fn add_alias(&self, alias: &str, tag: &str) -> Result<(), TagNameError> {
+        self.transaction(|lib| {
+            let tag = lib.create_tag(tag)?;
+            if lib.tag_exists(alias)? {
+                return Err(TagNameError::TagExists(String::from(alias)));
+            }
+            lib.insert_alias(alias, &tag)?;
+            Ok(())
+        })
+    }
+
+    fn insert_alias(&self, alias: &str, tag: &str) -> sqlite::Result<()> {
+        let mut statement = self.connection.prepare("INSERT OR REPLACE INTO tag_aliases (alias, tag_id) VALUES (?, (SELECT id FROM tags WHERE name=?));")?;
+        statement.bind(1, alias)?;
+        statement.bind(2, tag)?;
+        run(&mut statement)
+    }
+
+    pub fn remove_alias(&self, alias: &str) -> sqlite::Result<()> {
+        let mut statement = self.connection.prepare("DELETE FROM tag_aliases WHERE alias=?;")?;
+        statement.bind(1, alias)?;
+        run(&mut statement)
+    }
+
+    //Every tag in alphabetical order along with how many images have it
+    pub fn tag_usage(&self) -> sqlite::Result<Vec<(String, usize)>> {
+        let mut statement = self.connection.prepare("
+            SELECT tags.name, COUNT(image_tags.image_id) FROM tags
+            LEFT JOIN image_tags ON tags.id=image_tags.tag_id
+            GROUP BY tags.id ORDER BY tags.name;
+        ")?;
+        let mut usage = Vec::new();
+        while let State::Row = statement.next()? {
+            usage.push((statement.read::<String>(0)?, statement.read::<i64>(1)? as usize));
+        }
+        Ok(usage)
+    }
+
+    pub fn tag_exists(&self, tag: &str) -> sqlite::Result<bool> {
+        let mut statement = self.connection.prepare("SELECT COUNT(*) FROM tags WHERE name=?;")?;
+        statement.bind(1, tag)?;
+        statement.next()?;
+        Ok(statement.read::<i64>(0)? > 0)
+    }
+
+    //Renames a tag, keeping it on all of its images
+    //Refused if new_name is already a tag, which is what merge_tag is for, or an alias
+    pub fn rename_tag(&self, tag: &str, new_name: &str) -> Result<(), TagNameError> {
+        if tag == new_name {
+            return Ok(());
+        }
+        self.transaction(|lib| {
+            if lib.tag_exists(new_name)? {
+                return Err(TagNameError::TagExists(String::from(new_name)));
+            }
+            let resolved = lib.resolve_tag(new_name)?;
+            if resolved != new_name {
+                return Err(TagNameError::AliasExists(String::from(new_name), resolved));
+            }
+
+            let mut statement = lib.connection.prepare("UPDATE tags SET name=? WHERE name=?;")?;
+            statement.bind(1, new_name)?;
+            statement.bind(2, tag)?;
+            run(&mut statement)?;
+            Ok(())
+        })
+    }
+
+    //Moves every use of tag over to into, then deletes tag
+    //tag and its aliases become aliases of into so that typing them still finds the same images
+    //Its implications and parents move over too. Fails if that would make a loop of tags
+    pub fn merge_tag(&self, tag: &str, into: &str) -> Result<(), TagRelationError> {
+        self.transaction(|lib| {
+            let into = lib.create_tag(into)?;
+            if tag == into {
+                return Ok(());
+            }
+
+            for sql in [
+                "INSERT OR IGNORE INTO image_tags (image_id, tag_id)
+                 SELECT image_id, (SELECT id FROM tags WHERE name=?) FROM image_tags
+                 WHERE tag_id=(SELECT id FROM tags WHERE name=?);",
+                "UPDATE tag_aliases SET tag_id=(SELECT id FROM tags WHERE name=?) WHERE tag_id=(SELECT id FROM tags WHERE name=?);",
+                "UPDATE OR IGNORE tag_implications SET tag_id=(SELECT id FROM tags WHERE name=?) WHERE tag_id=(SELECT id FROM tags WHERE name=?);",
+                "UPDATE OR IGNORE tag_implications SET implied_id=(SELECT id FROM tags WHERE name=?) WHERE implied_id=(SELECT id FROM tags WHERE name=?);",
+                "UPDATE OR IGNORE tag_parents SET tag_id=(SELECT id FROM tags WHERE name=?) WHERE tag_id=(SELECT id FROM tags WHERE name=?);",
+                "UPDATE OR IGNORE tag_parents SET parent_id=(SELECT id FROM tags WHERE name=?) WHERE parent_id=(SELECT id FROM tags WHERE name=?);"
+            ] {
+                let mut statement = lib.connection.prepare(sql)?;
+                statement.bind(1, &*into)?;
+                statement.bind(2, tag)?;
+                run(&mut statement)?;
+            }
+
+            //A relation between the two tags is now one between into and itself, which means nothing
+            lib.connection.execute("DELETE FROM tag_implications WHERE tag_id=implied_id; DELETE FROM tag_parents WHERE tag_id=parent_id;")?;
+            for (table, column) in [("tag_implications", "implied_id"), ("tag_parents", "parent_id")] {
+                if let Some(path) = lib.relation_path(table, column, &into, &into)? {
+                    return Err(TagRelationError::Cycle(path));
+                }
+            }
+
+            //Both the images that just gained into and the ones that already had it get whatever it now materially implies
+            lib.insert_implied_tags(&into, "images.id IN (SELECT image_id FROM image_tags WHERE tag_id=(SELECT id FROM tags WHERE name=?))", &[sqlite::Value::String(into.clone())])?;
+            lib.delete_tag(tag)?;
+            lib.insert_alias(tag, &into)?;
+            Ok(())
+        })
+    }
+
+    //Deletes a tag. It comes off of its images via ON DELETE CASCADE
+    pub fn delete_tag(&self, tag: &str) -> sqlite::Result<()> {
+        let mut statement = self.connection.prepare("DELETE FROM tags WHERE name=?;")?;
+        statement.bind(1, tag)?;
+        run(&mut statement)
+    }
+
+    //All tags applied to the image in alphabetical order
+    pub fn tags_for_image(&self, hash: &str) -> sqlite::Result<Vec<String>> {
+        let mut statement = self.connection.prepare("
+            SELECT name FROM tags
+            JOIN
+            (SELECT tag_id FROM image_tags
+            WHERE image_tags.image_id = (
+                    SELECT id FROM images WHERE hash=?
+                ))
+            WHERE id=tag_id ORDER BY name;
+        ")?;
+        statement.bind(1, hash)?;
+        read_strings(&mut statement)
+    }
+
+    //Inserts the relationship between an image and an existing tag, along with the tags it materially implies
+    fn insert_image_tag(&self, hash: &str, tag: &str) -> sqlite::Result<()> {
+        let mut statement = self.connection.prepare("
+            INSERT OR IGNORE INTO image_tags VALUES (
+                    (SELECT id FROM images WHERE hash=?)
+                ,   (SELECT id FROM tags WHERE name=?)
+                );
+        ")?;
+        statement.bind(1, hash)?;
+        statement.bind(2, tag)?;
+        run(&mut statement)?;
+        self.insert_implied_tags(tag, "images.hash=?", &[sqlite::Value::String(String::from(hash))])
+    }
+
+    //Applies every tag that tag implies, directly or through other implications, to the images matching condition
+    //Only materialized implications are followed
+    fn insert_implied_tags(&self, tag: &str, condition: &str, params: &[sqlite::Value]) -> sqlite::Result<()> {
+        let mut statement = self.connection.prepare(format!("
+            WITH RECURSIVE implied(id) AS (
+                SELECT implied_id FROM tag_implications WHERE materialized AND tag_id=(SELECT id FROM tags WHERE name=?)
+                UNION SELECT tag_implications.implied_id FROM tag_implications JOIN implied ON tag_implications.tag_id=implied.id WHERE materialized
+            )
+            INSERT OR IGNORE INTO image_tags (image_id, tag_id) SELECT images.id, implied.id FROM images, implied WHERE {};
+        ", condition))?;
+        statement.bind(1, tag)?;
+        for (i, param) in params.iter().enumerate() {
+            statement.bind(i + 2, param)?;
+        }
+        run(&mut statement)
+    }
+
+    //The chain of tags leading from start to end through the relation stored in table, if there is one
+    //column is the one holding the related tag. With start and end the same, this finds a loop through start
+    fn relation_path(&self, table: &str, column: &str, start: &str, end: &str) -> sqlite::Result<Option<Vec<String>>> {
+        let mut statement = self.connection.prepare(format!("
+            SELECT a.name, b.name FROM {} AS r
+            JOIN tags AS a ON a.id=r.tag_id
+            JOIN tags AS b ON b.id=r.{};
+        ", table, column))?;
+        let mut edges: HashMap<String, Vec<String>> = HashMap::new();
+        while let State::Row = statement.next()? {
+            edges.entry(statement.read::<String>(0)?).or_default().push(statement.read::<String>(1)?);
+        }
+
+        //Breadth first, so that the shortest chain is the one reported
+        let mut came_from: HashMap<String, String> = HashMap::new();
+        let mut queue = VecDeque::new();
+        queue.push_back(String::from(start));
+        while let Some(current) = queue.pop_front() {
+            for next in edges.get(&current).into_iter().flatten() {
+                if next == end {
+                    let mut path = vec![next.clone(), current.clone()];
+                    while let Some(previous) = came_from.get(path.last().unwrap()) {
+                        path.push(previous.clone());
+                    }
+                    path.reverse();
+                    return Ok(Some(path));
+                }
+                if next != start && !came_from.contains_key(next) {
+                    came_from.insert(next.clone(), current.clone());
+                    queue.push_back(next.clone());
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    //Refuses a relation from tag to other that would relate a tag to itself
+    fn check_relation(&self, table: &str, column: &str, tag: &str, other: &str) -> Result<(), TagRelationError> {
+        if tag == other {
+            return Err(TagRelationError::SameTag);
+        }
+        match self.relation_path(table, column, other, tag)? {
+            Some(path) => {
+                let mut cycle = vec![String::from(tag)];
+                cycle.extend(path);
+                Err(TagRelationError::Cycle(cycle))
+            }
+            None => { Ok(()) }
+        }
+    }
+
+    //Makes tag imply implied. If materialize is set, implied is applied to the images that already have tag,
+    //and to every image tag gets applied to from now on
+    pub fn add_implication(&self, tag: &str, implied: &str, materialize: bool) -> Result<(), TagRelationError> {
+        self.transaction(|lib| {
+            let tag = lib.create_tag(tag)?;
+            let implied = lib.create_tag(implied)?;
+            lib.check_relation("tag_implications", "implied_id", &tag, &implied)?;
+
+            let mut statement = lib.connection.prepare("
+                INSERT OR REPLACE INTO tag_implications (tag_id, implied_id, materialized)
+                VALUES ((SELECT id FROM tags WHERE name=?), (SELECT id FROM tags WHERE name=?), ?);
+            ")?;
+            statement.bind(1, &*tag)?;
+            statement.bind(2, &*implied)?;
+            statement.bind(3, materialize as i64)?;
+            run(&mut statement)?;
+
+            if materialize {
+                lib.insert_implied_tags(&tag, "images.id IN (SELECT image_id FROM image_tags WHERE tag_id=(SELECT id FROM tags WHERE name=?))", &[sqlite::Value::String(tag.clone())])?;
+            }
+            Ok(())
+        })
+    }
+
+    //Stops tag implying implied. Tags that were already materialized stay on their images
+    pub fn remove_implication(&self, tag: &str, implied: &str) -> sqlite::Result<()> {
+        let mut statement = self.connection.prepare("
+            DELETE FROM tag_implications
+            WHERE tag_id=(SELECT id FROM tags WHERE name=?) AND implied_id=(SELECT id FROM tags WHERE name=?);
+        ")?;
+        statement.bind(1, tag)?;
+        statement.bind(2, implied)?;
+        run(&mut statement)
+    }
+
+    //Every implication ordered by tag
+    pub fn implications(&self) -> sqlite::Result<Vec<Implication>> {
+        let mut statement = self.connection.prepare("
+            SELECT a.name, b.name, materialized FROM tag_implications
+            JOIN tags AS a ON a.id=tag_implications.tag_id
+            JOIN tags AS b ON b.id=tag_implications.implied_id
+            ORDER BY a.name, b.name;
+        ")?;
+        let mut implications = Vec::new();
+        while let State::Row = statement.next()? {
+            implications.push(Implication {
+                tag: statement.read::<String>(0)?,
+                implied: statement.read::<String>(1)?,
+                materialized: statement.read::<i64>(2)? != 0
+            });
+        }
+        Ok(implications)
+    }
+
+    //Puts tag under parent in the tag hierarchy
+    pub fn add_parent(&self, tag: &str, parent: &str) -> Result<(), TagRelationError> {
+        self.transaction(|lib| {
+            let tag = lib.create_tag(tag)?;
+            let parent = lib.create_tag(parent)?;
+            lib.check_relation("tag_parents", "parent_id", &tag, &parent)?;
+
+            let mut statement = lib.connection.prepare("
+                INSERT OR IGNORE INTO tag_parents (tag_id, parent_id)
+                VALUES ((SELECT id FROM tags WHERE name=?), (SELECT id FROM tags WHERE name=?));
+            ")?;
+            statement.bind(1, &*tag)?;
+            statement.bind(2, &*parent)?;
+            run(&mut statement)?;
+            Ok(())
+        })
+    }
+
+    pub fn remove_parent(&self, tag: &str, parent: &str) -> sqlite::Result<()> {
+        let mut statement = self.connection.prepare("
+            DELETE FROM tag_parents
+            WHERE tag_id=(SELECT id FROM tags WHERE name=?) AND parent_id=(SELECT id FROM tags WHERE name=?);
+        ")?;
+        statement.bind(1, tag)?;
+        statement.bind(2, parent)?;
+        run(&mut statement)
+    }
+
+    //Every (tag, parent) pair ordered by tag
+    pub fn parents(&self) -> sqlite::Result<Vec<(String, String)>> {
+        let mut statement = self.connection.prepare("
+            SELECT a.name, b.name FROM tag_parents
+            JOIN tags AS a ON a.id=tag_parents.tag_id
+            JOIN tags AS b ON b.id=tag_parents.parent_id
+            ORDER BY a.name, b.name;
+        ")?;
+        let mut parents = Vec::new();
+        while let State::Row = statement.next()? {
+            parents.push((statement.read::<String>(0)?, statement.read::<String>(1)?));
+        }
+        Ok(parents)
+    }
+
+    //Makes sure there's a row for the image before it gets tagged
+    //Images opened before the library was can be tagged without ever having been registered
+    fn ensure_image(&self, name: &str, hash: &str) -> sqlite::Result<()> {
+        match self.image_name_for_hash(hash)? {
+            Some(_) => { Ok(()) }
+            None => { self.register_image(name, hash) }
+        }
+    }
+
+    //Applies tag to the image stored as name, creating the image and tag rows if necessary
+    //Returns the name of the tag that was applied, which differs from tag if it's an alias
+    pub fn add_tag_to_image(&self, name: &str, hash: &str, tag: &str) -> sqlite::Result<String> {
+        self.transaction(|lib| {
+            lib.ensure_image(name, hash)?;
+            let tag = lib.create_tag(tag)?;
+            lib.insert_image_tag(hash, &tag)?;
+            Ok(tag)
+        })
+    }
+
+    //Applies tag to every one of the (name, hash) images in a single transaction
+    pub fn add_tag_to_images(&self, images: &[(&str, &str)], tag: &str) -> sqlite::Result<()> {
+        self.transaction(|lib| {
+            let tag = lib.create_tag(tag)?;
+            for (name, hash) in images {
+                lib.ensure_image(name, hash)?;
+                lib.insert_image_tag(hash, &tag)?;
+            }
+            Ok(())
+        })
+    }
+
+    //Removes tag from every one of the images in a single transaction
+    pub fn remove_tag_from_images(&self, hashes: &[&str], tag: &str) -> sqlite::Result<()> {
+        self.transaction(|lib| {
+            for hash in hashes {
+                lib.remove_tag_from_image(hash, tag)?;
+            }
+            Ok(())
+        })
+    }
+
+    pub fn remove_tag_from_image(&self, hash: &str, tag: &str) -> sqlite::Result<()> {
+        let mut statement = self.connection.prepare("
+            DELETE FROM image_tags WHERE image_id=(
+                SELECT id FROM images WHERE hash=?
+            ) AND tag_id=(
+                SELECT id FROM tags WHERE name=?
+            );
+        ")?;
+        statement.bind(1, hash)?;
+        statement.bind(2, tag)?;
+        run(&mut statement)
+    }
+
+    //Every image with the given tag, or with an alias of it or a tag that implies it
+    pub fn images_with_tag(&self, tag: &str, order: &SortOrder) -> sqlite::Result<Vec<ImageRow>> {
+        self.query_images(&Some(query::Expr::Tag(String::from(tag))), order)
+    }
+
+    //Every image matching a search query
+    pub fn query_images(&self, expr: &Option<query::Expr>, order: &SortOrder) -> sqlite::Result<Vec<ImageRow>> {
+        let ids = self.query_image_ids(expr, order)?;
+        self.images_with_ids(&ids)
+    }
+
+    //The ids of every image matching a search query, in order
+    //The gallery pages through these instead of the live query, so that tagging images that are already shown
+    //doesn't shift the ones that haven't been loaded yet into a page that was already fetched
+    pub fn query_image_ids(&self, expr: &Option<query::Expr>, order: &SortOrder) -> sqlite::Result<Vec<i64>> {
+        let (condition, params) = query::to_sql(expr);
+        let mut statement = self.connection.prepare(format!("SELECT images.id FROM images WHERE {} ORDER BY {};", condition, order.to_sql()))?;
+        for (i, param) in params.iter().enumerate() {
+            statement.bind(i + 1, param)?;
+        }
+
+        let mut ids = Vec::new();
+        while let State::Row = statement.next()? {
+            ids.push(statement.read::<i64>(0)?);
+        }
+        Ok(ids)
+    }
+
+    //The images with the given ids in the same order. Ids whose image has since been deleted are skipped
+    pub fn images_with_ids(&self, ids: &[i64]) -> sqlite::Result<Vec<ImageRow>> {
+        let mut found = HashMap::new();
+
+        //Stay well below sqlite's limit on the number of parameters in one statement
+        for chunk in ids.chunks(500) {
+            let placeholders = vec!["?"; chunk.len()].join(", ");
+            let mut statement = self.connection.prepare(format!("
+                SELECT id, path, hash, width, height, size, format, modified FROM images WHERE id IN ({});
+            ", placeholders))?;
+            for (i, id) in chunk.iter().enumerate() {
+                statement.bind(i + 1, *id)?;
+            }
+            while let State::Row = statement.next()? {
+                let hash = match statement.read::<sqlite::Value>(2)? {
+                    sqlite::Value::String(h) => { Some(h) }
+                    _ => { None }
+                };
+                found.insert(statement.read::<i64>(0)?, ImageRow {
+                    name: statement.read::<String>(1)?,
+                    hash,
+                    metadata: read_metadata(&statement, 3)?
+                });
+            }
+        }
+        Ok(ids.iter().filter_map(|id| found.remove(id)).collect())
+    }
+
+    //Number of rows changed by the last statement
+    fn changes(&self) -> sqlite::Result<usize> {
+        let mut statement = self.connection.prepare("SELECT changes();")?;
+        statement.next()?;
+        Ok(statement.read::<i64>(0)? as usize)
+    }
+
+    fn insert_tag_for_query(&self, expr: &Option<query::Expr>, tag: &str) -> sqlite::Result<usize> {
+        let (condition, params) = query::to_sql(expr);
+        let mut statement = self.connection.prepare(format!("
+            INSERT OR IGNORE INTO image_tags (image_id, tag_id)
+            SELECT images.id, (SELECT id FROM tags WHERE name=?) FROM images WHERE {};
+        ", condition))?;
+        statement.bind(1, tag)?;
+        for (i, param) in params.iter().enumerate() {
+            statement.bind(i + 2, param)?;
+        }
+        run(&mut statement)?;
+        let changes = self.changes()?;
+        self.insert_implied_tags(tag, &condition, &params)?;
+        Ok(changes)
+    }
+
+    fn delete_tag_for_query(&self, expr: &Option<query::Expr>, tag: &str) -> sqlite::Result<usize> {
+        let (condition, params) = query::to_sql(expr);
+        let mut statement = self.connection.prepare(format!("
+            DELETE FROM image_tags WHERE tag_id=(SELECT id FROM tags WHERE name=?)
+            AND image_id IN (SELECT images.id FROM images WHERE {});
+        ", condition))?;
+        statement.bind(1, tag)?;
+        for (i, param) in params.iter().enumerate() {
+            statement.bind(i + 2, param)?;
+        }
+        run(&mut statement)?;
+        self.changes()
+    }
+
+    //Applies tag to every image matching a search query in a single transaction
+    //Returns how many images didn't already have it
+    pub fn add_tag_to_query(&self, expr: &Option<query::Expr>, tag: &str) -> sqlite::Result<usize> {
+        self.transaction(|lib| {
+            let tag = lib.create_tag(tag)?;
+            lib.insert_tag_for_query(expr, &tag)
+        })
+    }
+
+    //Removes tag from every image matching a search query in a single transaction
+    //Returns how many images had it
+    pub fn remove_tag_from_query(&self, expr: &Option<query::Expr>, tag: &str) -> sqlite::Result<usize> {
+        self.transaction(|lib| {
+            let tag = lib.resolve_tag(tag)?;
+            lib.delete_tag_for_query(expr, &tag)
+        })
+    }
+
+    //Swaps from for to on every image matching a search query that has from, in a single transaction
+    //Returns how many images lost from
+    pub fn replace_tag_in_query(&self, expr: &Option<query::Expr>, from: &str, to: &str) -> sqlite::Result<usize> {
+        let with_from = query::and_tag(expr, from);
+        self.transaction(|lib| {
+            let from = lib.resolve_tag(from)?;
+            let to = lib.create_tag(to)?;
+            if from == to {
+                return Ok(0);
+            }
+            lib.insert_tag_for_query(&with_from, &to)?;
+            lib.delete_tag_for_query(&with_from, &from)
+        })
+    }
+
+    //Number of images matching a search query
+    pub fn count_images(&self, expr: &Option<query::Expr>) -> sqlite::Result<usize> {
+        let (condition, params) = query::to_sql(expr);
+        let mut statement = self.connection.prepare(format!("SELECT COUNT(*) FROM images WHERE {};", condition))?;
+        for (i, param) in params.iter().enumerate() {
+            statement.bind(i + 1, param)?;
+        }
+        statement.next()?;
+        Ok(statement.read::<i64>(0)? as usize)
+    }
+
+}
+
+//Steps a statement that doesn't return any rows to completion
+fn run(statement: &mut sqlite::Statement) -> sqlite::Result<()> {
+    while let State::Row = statement.next()? {}
+    Ok(())
+}
+
+//Reads the width, height, size, format and modified columns starting at column first
+//None if any of them other than modified hasn't been recorded
+fn read_metadata(statement: &sqlite::Statement, first: usize) -> sqlite::Result<Option<ImageMetadata>> {
+    let mut values = Vec::with_capacity(5);
+    for i in first..first + 5 {
+        values.push(statement.read::<sqlite::Value>(i)?);
+    }
+    match values.as_slice() {
+        [sqlite::Value::Integer(width), sqlite::Value::Integer(height), sqlite::Value::Integer(size), sqlite::Value::String(format), modified] => {
+            let modified = match modified {
+                sqlite::Value::Integer(t) => { Some(*t) }
+                _ => { None }
+            };
+            Ok(Some(ImageMetadata {
+                width: *width as u32,
+                height: *height as u32,
+                size: *size as u64,
+                format: format.clone(),
+                modified
+            }))
+        }
+        _ => { Ok(None) }
+    }
+}
+
+//Collects the first column of every row as a String
+fn read_strings(statement: &mut sqlite::Statement) -> sqlite::Result<Vec<String>> {
+    let mut strings = Vec::new();
+    while let State::Row = statement.next()? {
+        strings.push(statement.read::<String>(0)?);
+    }
+    Ok(strings)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn library() -> Library {
+        match Library::open_in_memory() {
+            Ok(lib) => { lib }
+            Err(e) => { panic!("Couldn't open the library: {}", e) }
+        }
+    }
+
+    //Adds images stored as "<hash>.png"
+    fn add_images(lib: &Library, hashes: &[&str]) {
+        for hash in hashes {
+            lib.add_image(&format!("{}.png", hash), hash).unwrap();
+        }
+    }
+
+    fn parsed(query: &str) -> Option<query::Expr> {
+        match query::parse(query) {
+            Ok(expr) => { expr }
+            Err(e) => { panic!("Couldn't parse {:?}: {}", query, e.message) }
+        }
+    }
+
+    fn order(key: SortKey, seed: i32) -> SortOrder {
+        SortOrder {
+            key,
+            descending: false,
+            seed
+        }
+    }
+
+    //Names of the images matching a search query in filename order
+    fn names(lib: &Library, query: &str) -> Vec<String> {
+        lib.query_images(&parsed(query), &order(SortKey::Filename, 0)).unwrap().into_iter().map(|row| row.name).collect()
+    }
+
+    //Tags one of the images added by add_images
+    fn apply_tag(lib: &Library, hash: &str, tag: &str) {
+        lib.add_tag_to_image(&format!("{}.png", hash), hash, tag).unwrap();
+    }
+
+    fn refused(result: Result<(), TagRelationError>) -> TagRelationError {
+        match result {
+            Ok(()) => { panic!("The relation should have been refused") }
+            Err(e) => { e }
+        }
+    }
+
+    fn related(result: Result<(), TagRelationError>) {
+        if let Err(e) = result {
+            panic!("The relation should have been allowed: {}", e);
+        }
+    }
+
+    fn named(result: Result<(), TagNameError>) {
+        if let Err(e) = result {
+            panic!("The name should have been allowed: {}", e);
+        }
+    }
+
+    #[test]
+    fn tags_are_applied_and_removed() {
+        let lib = library();
+        add_images(&lib, &["a", "b"]);
+        apply_tag(&lib, "a", "cat");
+        apply_tag(&lib, "b", "cat");
+        apply_tag(&lib, "b", "say \"hi\"");
+        assert_eq!(lib.tags_for_image("b").unwrap(), ["cat", "say \"hi\""]);
+        assert_eq!(lib.all_tags().unwrap(), ["cat", "say \"hi\""]);
+
+        lib.remove_tag_from_image("a", "cat").unwrap();
+        assert!(lib.tags_for_image("a").unwrap().is_empty());
+        assert_eq!(lib.tags_for_image("b").unwrap().len(), 2);
+
+        lib.delete_image("b").unwrap();
+        assert!(lib.tags_for_image("b").unwrap().is_empty());
+        assert!(lib.image_name_for_hash("b").unwrap().is_none());
+    }
+
+    #[test]
+    fn tagging_adds_images_that_are_not_in_the_library_yet() {
+        let lib = library();
+        lib.add_tag_to_image("new.png", "n", "cat").unwrap();
+        lib.add_tag_to_images(&[("new.png", "n"), ("other.png", "o")], "dog").unwrap();
+
+        assert_eq!(lib.image_name_for_hash("n").unwrap(), Some(String::from("new.png")));
+        assert_eq!(lib.image_name_for_hash("o").unwrap(), Some(String::from("other.png")));
+        assert_eq!(lib.tags_for_image("n").unwrap(), ["cat", "dog"]);
+        assert_eq!(lib.tags_for_image("o").unwrap(), ["dog"]);
+    }
+
+    #[test]
+    fn unhashed_duplicate_is_folded_into_the_hashed_row() {
+        let lib = library();
+        add_images(&lib, &["a"]);
+        apply_tag(&lib, "a", "cat");
+        lib.create_tag("dog").unwrap();
+        lib.connection.execute("
+            INSERT INTO images (path) VALUES ('old.png');
+            INSERT INTO image_tags (image_id, tag_id) SELECT images.id, tags.id FROM images, tags WHERE path='old.png' AND name='dog';
+        ").unwrap();
+        assert!(matches!(lib.image_hash_for_name("old.png").unwrap(), Some(None)));
+
+        //A hashed row is never folded into itself
+        assert!(!lib.merge_unhashed_duplicate("a.png", "a").unwrap());
+
+        assert!(lib.merge_unhashed_duplicate("old.png", "a").unwrap());
+        assert_eq!(lib.tags_for_image("a").unwrap(), ["cat", "dog"]);
+        assert!(lib.image_hash_for_name("old.png").unwrap().is_none());
+        assert!(!lib.merge_unhashed_duplicate("old.png", "a").unwrap());
+    }
+
+    #[test]
+    fn merging_duplicates_keeps_every_tag() {
+        let lib = library();
+        add_images(&lib, &["a", "b", "c"]);
+        apply_tag(&lib, "a", "cat");
+        apply_tag(&lib, "b", "dog");
+        apply_tag(&lib, "c", "cat");
+        apply_tag(&lib, "c", "fox");
+
+        lib.merge_duplicates("a", &["b"], false).unwrap();
+        assert_eq!(lib.tags_for_image("a").unwrap(), ["cat", "dog"]);
+        assert!(lib.image_name_for_hash("b").unwrap().is_some());
+
+        lib.merge_duplicates("a", &["b", "c"], true).unwrap();
+        assert_eq!(lib.tags_for_image("a").unwrap(), ["cat", "dog", "fox"]);
+        assert!(lib.image_name_for_hash("b").unwrap().is_none());
+        assert!(lib.image_name_for_hash("c").unwrap().is_none());
+    }
+
+    #[test]
+    fn boolean_searches() {
+        let lib = library();
+        add_images(&lib, &["a", "b", "c"]);
+        for hash in ["a", "b", "c"] {
+            apply_tag(&lib, hash, "cat");
+        }
+        apply_tag(&lib, "b", "dog");
+        apply_tag(&lib, "c", "two words");
+
+        assert_eq!(names(&lib, "cat"), ["a.png", "b.png", "c.png"]);
+        assert_eq!(names(&lib, "cat NOT dog"), ["a.png", "c.png"]);
+        assert_eq!(names(&lib, "dog OR nothing"), ["b.png"]);
+        assert_eq!(names(&lib, "cat (dog OR \"two words\")"), ["b.png", "c.png"]);
+        assert_eq!(names(&lib, ""), ["a.png", "b.png", "c.png"]);
+        assert_eq!(lib.count_images(&parsed("NOT dog")).unwrap(), 2);
+    }
+
+    #[test]
+    fn metadata_searches() {
+        let lib = library();
+        add_images(&lib, &["a", "b", "c"]);
+        let metadata = |width, height, format: &str, modified| ImageMetadata {
+            width,
+            height,
+            size: 1024,
+            format: String::from(format),
+            modified: Some(modified)
+        };
+
+        //Noon on 2021-05-01 and midnight at the start of 2021-05-02, UTC
+        lib.set_image_metadata("a", &metadata(1920, 1080, "png", 1619870400)).unwrap();
+        lib.set_image_metadata("b", &metadata(600, 800, "jpeg", 1619913600)).unwrap();
+        apply_tag(&lib, "b", "cat");
+
+        assert_eq!(names(&lib, "width:>=1920"), ["a.png"]);
+        assert_eq!(names(&lib, "ratio:portrait"), ["b.png"]);
+        assert_eq!(names(&lib, "format:jpg"), ["b.png"]);
+        assert_eq!(names(&lib, "tagcount:0"), ["a.png", "c.png"]);
+
+        //A date stands for the whole day
+        assert_eq!(names(&lib, "modified:2021-05-01"), ["a.png"]);
+        assert_eq!(names(&lib, "modified:<=2021-05-01"), ["a.png"]);
+        assert_eq!(names(&lib, "modified:>2021-05-01"), ["b.png"]);
+        assert_eq!(names(&lib, "modified:<2021-05-02"), ["a.png"]);
+        assert_eq!(names(&lib, "modified:>=2021-05-02"), ["b.png"]);
+        assert_eq!(names(&lib, "modified:2021-05-02"), ["b.png"]);
+
+        //c has no metadata recorded, so it never matches a metadata filter, not even a negated one
+        assert_eq!(names(&lib, "NOT width:>=1920"), ["b.png"]);
+    }
+
+    #[test]
+    fn images_with_tag_follows_the_sort_order() {
+        let lib = library();
+        add_images(&lib, &["a", "b", "c"]);
+        apply_tag(&lib, "a", "cat");
+        apply_tag(&lib, "c", "cat");
+        apply_tag(&lib, "c", "dog");
+
+        let with_tag = |key, descending| -> Vec<String> {
+            let order = SortOrder {
+                key,
+                descending,
+                seed: 0
+            };
+            lib.images_with_tag("cat", &order).unwrap().into_iter().map(|row| row.name).collect()
+        };
+        assert_eq!(with_tag(SortKey::Filename, false), ["a.png", "c.png"]);
+        assert_eq!(with_tag(SortKey::Filename, true), ["c.png", "a.png"]);
+        assert_eq!(with_tag(SortKey::TagCount, true), ["c.png", "a.png"]);
+    }
+
+    #[test]
+    fn random_order_depends_on_the_seed() {
+        let lib = library();
+        let hashes: Vec<String> = (0..32).map(|i| format!("{:02}", i)).collect();
+        add_images(&lib, &hashes.iter().map(|h| h.as_str()).collect::<Vec<_>>());
+
+        let shuffled = |seed| -> Vec<String> {
+            lib.query_images(&None, &order(SortKey::Random, seed)).unwrap().into_iter().map(|row| row.name).collect()
+        };
+        let first = shuffled(1);
+        let second = shuffled(2);
+        assert_eq!(first, shuffled(1));
+        assert_ne!(first, second);
+
+        //A different seed has to actually reshuffle, not just start the same cycle somewhere else
+        let start = first.iter().position(|name| *name == second[0]).unwrap();
+        let rotated: Vec<String> = first[start..].iter().chain(&first[..start]).cloned().collect();
+        assert_ne!(rotated, second);
+
+        let mut sorted = first.clone();
+        sorted.sort();
+        assert_eq!(sorted, names(&lib, ""));
+    }
+
+    #[test]
+    fn tagging_a_search_result_counts_what_changed() {
+        let lib = library();
+        add_images(&lib, &["a", "b", "c"]);
+        apply_tag(&lib, "a", "cat");
+        apply_tag(&lib, "b", "cat");
+        apply_tag(&lib, "b", "dog");
+
+        //Images that already have the tag aren't counted again
+        assert_eq!(lib.add_tag_to_query(&parsed("cat"), "pet").unwrap(), 2);
+        assert_eq!(lib.add_tag_to_query(&None, "pet").unwrap(), 1);
+        assert_eq!(names(&lib, "pet"), ["a.png", "b.png", "c.png"]);
+
+        assert_eq!(lib.remove_tag_from_query(&parsed("NOT dog"), "pet").unwrap(), 2);
+        assert_eq!(names(&lib, "pet"), ["b.png"]);
+
+        assert_eq!(lib.replace_tag_in_query(&None, "cat", "feline").unwrap(), 2);
+        assert_eq!(lib.tags_for_image("b").unwrap(), ["dog", "feline", "pet"]);
+        assert_eq!(names(&lib, "cat"), Vec::<String>::new());
+        assert_eq!(lib.count_images(&parsed("feline")).unwrap(), 2);
+    }
+
+    #[test]
+    fn tags_are_renamed_merged_and_deleted() {
+        let lib = library();
+        add_images(&lib, &["a", "b", "c"]);
+        apply_tag(&lib, "a", "cat");
+        apply_tag(&lib, "b", "cat");
+        apply_tag(&lib, "b", "kitten");
+        apply_tag(&lib, "c", "kitten");
+        lib.create_tag("unused").unwrap();
+        assert_eq!(lib.tag_usage().unwrap(), [(String::from("cat"), 2), (String::from("kitten"), 2), (String::from("unused"), 0)]);
+
+        named(lib.rename_tag("cat", "feline"));
+        assert_eq!(names(&lib, "feline"), ["a.png", "b.png"]);
+
+        //Renaming onto a tag that already exists is left to merge_tag
+        assert!(matches!(lib.rename_tag("kitten", "feline"), Err(TagNameError::TagExists(_))));
+        assert_eq!(lib.tags_for_image("c").unwrap(), ["kitten"]);
+
+        related(lib.merge_tag("kitten", "feline"));
+        assert_eq!(lib.tag_usage().unwrap(), [(String::from("feline"), 3), (String::from("unused"), 0)]);
+        assert_eq!(lib.tags_for_image("b").unwrap(), ["feline"]);
+
+        lib.delete_tag("feline").unwrap();
+        assert!(lib.tags_for_image("a").unwrap().is_empty());
+        assert_eq!(lib.all_tags().unwrap(), ["unused"]);
+    }
+
+    #[test]
+    fn aliases_stand_for_their_tag() {
+        let lib = library();
+        add_images(&lib, &["a", "b"]);
+        named(lib.add_alias("kitty", "cat"));
+
+        assert_eq!(lib.add_tag_to_image("a.png", "a", "kitty").unwrap(), "cat");
+        assert_eq!(lib.all_tags().unwrap(), ["cat"]);
+        assert_eq!(lib.aliases().unwrap(), [(String::from("kitty"), String::from("cat"))]);
+        assert_eq!(names(&lib, "kitty"), ["a.png"]);
+
+        //Tag names and alias names never overlap, whichever side the clash comes from
+        assert!(matches!(lib.add_alias("cat", "cat"), Err(TagNameError::TagExists(_))));
+        assert!(matches!(lib.add_alias("cat", "dog"), Err(TagNameError::TagExists(_))));
+        assert!(matches!(lib.rename_tag("cat", "kitty"), Err(TagNameError::AliasExists(_, _))));
+        assert_eq!(lib.all_tags().unwrap(), ["cat"]);
+
+        assert_eq!(lib.add_tag_to_query(&parsed("NOT cat"), "kitty").unwrap(), 1);
+        assert_eq!(lib.remove_tag_from_query(&None, "kitty").unwrap(), 2);
+        assert_eq!(names(&lib, "cat"), Vec::<String>::new());
+
+        lib.remove_alias("kitty").unwrap();
+        assert_eq!(lib.resolve_tag("kitty").unwrap(), "kitty");
+    }
+
+    #[test]
+    fn merged_tag_becomes_an_alias() {
+        let lib = library();
+        add_images(&lib, &["a", "b"]);
+        apply_tag(&lib, "a", "cat");
+        apply_tag(&lib, "b", "feline");
+        named(lib.add_alias("kitty", "feline"));
+
+        related(lib.merge_tag("feline", "cat"));
+        assert_eq!(lib.all_tags().unwrap(), ["cat"]);
+        assert_eq!(lib.resolve_tag("feline").unwrap(), "cat");
+        assert_eq!(lib.resolve_tag("kitty").unwrap(), "cat");
+        assert_eq!(names(&lib, "feline"), ["a.png", "b.png"]);
+    }
+
+    #[test]
+    fn merge_that_would_make_a_loop_changes_nothing() {
+        let lib = library();
+        add_images(&lib, &["a"]);
+        apply_tag(&lib, "a", "b");
+        related(lib.add_implication("a", "b", false));
+        related(lib.add_implication("c", "a", false));
+
+        assert!(matches!(refused(lib.merge_tag("b", "c")), TagRelationError::Cycle(_)));
+        assert_eq!(lib.all_tags().unwrap(), ["a", "b", "c"]);
+        assert_eq!(lib.implications().unwrap().len(), 2);
+        assert_eq!(lib.tags_for_image("a").unwrap(), ["b"]);
+        assert_eq!(lib.resolve_tag("b").unwrap(), "b");
+    }
+
+    #[test]
+    fn implications_are_materialized_or_searched() {
+        let lib = library();
+        add_images(&lib, &["a", "b", "c"]);
+        apply_tag(&lib, "a", "fox");
+
+        //Existing images get the implied tag, and so do ones tagged later
+        related(lib.add_implication("fox", "animal", true));
+        assert_eq!(lib.tags_for_image("a").unwrap(), ["animal", "fox"]);
+        apply_tag(&lib, "b", "fox");
+        assert_eq!(lib.tags_for_image("b").unwrap(), ["animal", "fox"]);
+
+        //A search-only implication is never written to images, but searching for it still finds them
+        related(lib.add_implication("animal", "living", false));
+        apply_tag(&lib, "c", "animal");
+        assert_eq!(lib.tags_for_image("c").unwrap(), ["animal"]);
+        assert_eq!(names(&lib, "living"), ["a.png", "b.png", "c.png"]);
+
+        lib.remove_implication("animal", "living").unwrap();
+        assert_eq!(names(&lib, "living"), Vec::<String>::new());
+        assert_eq!(lib.implications().unwrap().len(), 1);
+
+        //Merging hands vulpine's implication over to fox, and d picks up everything fox implies now that it has fox
+        apply_tag(&lib, "d", "vulpine");
+        related(lib.add_implication("vulpine", "wild", true));
+        related(lib.merge_tag("vulpine", "fox"));
+        assert_eq!(lib.tags_for_image("a").unwrap(), ["animal", "fox", "wild"]);
+        assert_eq!(lib.tags_for_image("d").unwrap(), ["animal", "fox", "wild"]);
+    }
+
+    #[test]
+    fn relations_that_make_a_loop_are_refused() {
+        let lib = library();
+        related(lib.add_implication("a", "b", true));
+        related(lib.add_implication("b", "c", true));
+
+        match refused(lib.add_implication("c", "a", true)) {
+            TagRelationError::Cycle(path) => { assert_eq!(path, ["c", "a", "b", "c"]); }
+            e => { panic!("Expected a cycle, got: {}", e) }
+        }
+        assert!(matches!(refused(lib.add_implication("a", "a", true)), TagRelationError::SameTag));
+        assert_eq!(lib.implications().unwrap().len(), 2);
+
+        related(lib.add_parent("x", "y"));
+        match refused(lib.add_parent("y", "x")) {
+            TagRelationError::Cycle(path) => { assert_eq!(path, ["y", "x", "y"]); }
+            e => { panic!("Expected a cycle, got: {}", e) }
+        }
+        assert_eq!(lib.parents().unwrap(), [(String::from("x"), String::from("y"))]);
+    }
+
+    #[test]
+    fn under_finds_descendants() {
+        let lib = library();
+        add_images(&lib, &["a", "b", "c"]);
+        related(lib.add_parent("cat", "animal"));
+        related(lib.add_parent("kitten", "cat"));
+        apply_tag(&lib, "a", "kitten");
+        apply_tag(&lib, "b", "animal");
+        apply_tag(&lib, "c", "dog");
+
+        assert_eq!(names(&lib, "under:animal"), ["a.png", "b.png"]);
+        assert_eq!(names(&lib, "animal"), ["b.png"]);
+        assert_eq!(names(&lib, "under:cat"), ["a.png"]);
+    }
+
+    #[test]
+    fn replacing_only_touches_images_that_carry_the_tag() {
+        let lib = library();
+        add_images(&lib, &["a", "b"]);
+        related(lib.add_implication("cat", "animal", false));
+        apply_tag(&lib, "a", "cat");
+        apply_tag(&lib, "b", "animal");
+        assert_eq!(names(&lib, "animal"), ["a.png", "b.png"]);
+
+        assert_eq!(lib.replace_tag_in_query(&parsed("animal"), "animal", "beast").unwrap(), 1);
+        assert_eq!(lib.tags_for_image("a").unwrap(), ["cat"]);
+        assert_eq!(lib.tags_for_image("b").unwrap(), ["beast"]);
+        assert_eq!(lib.replace_tag_in_query(&None, "beast", "beast").unwrap(), 0);
+    }
+
+    #[test]
+    fn paging_through_ids_is_not_shifted_by_tagging() {
+        let lib = library();
+        add_images(&lib, &["a", "b", "c", "d", "e"]);
+        let ids = lib.query_image_ids(&parsed("tagcount:0"), &order(SortKey::Filename, 0)).unwrap();
+        let page = |range: std::ops::Range<usize>| -> Vec<String> {
+            lib.images_with_ids(&ids[range]).unwrap().into_iter().map(|row| row.name).collect()
+        };
+        assert_eq!(page(0..2), ["a.png", "b.png"]);
+
+        //Tagging the first page takes it out of the query, but the next page still starts after it
+        apply_tag(&lib, "a", "cat");
+        apply_tag(&lib, "b", "cat");
+        assert_eq!(page(2..4), ["c.png", "d.png"]);
+
+        //Deleted images are skipped rather than failing the page
+        lib.delete_image("e").unwrap();
+        assert_eq!(page(4..5), Vec::<String>::new());
+    }
+}
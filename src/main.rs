@@ -3,22 +3,29 @@ extern crate nalgebra_glm as glm;
 extern crate tinyfiledialogs as tfd;
 extern crate ozy_engine as ozy;
 
-use sqlite::State;
+use std::collections::HashSet;
 use std::path::Path;
 use std::mem::size_of;
 use std::process::{exit};
 use std::{fs, thread};
-use std::sync::mpsc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use glfw::{Action, Context, Key, MouseButton, WindowEvent, WindowMode};
-use imgui::{Condition, DrawCmd, FontAtlasRefMut, ImageButton, ImString, MenuItem, TextureId, WindowFocusedFlags};
+use imgui::{Condition, DrawCmd, FontAtlasRefMut, ImageButton, ImString, MenuItem, StyleColor, TextureId, WindowFocusedFlags};
 use ozy::glutil;
 use ozy::render::{clip_from_screen};
 use gl::types::*;
 use tfd::{MessageBoxIcon, YesNo};
 
+use uwu_db::{library, query};
+use uwu_db::library::{ImageFingerprint, ImageMetadata, ImageRow, Implication, Library, SortKey, SortOrder, TagNameError, TagRelationError};
 use crate::structs::*;
 
+mod animation;
+mod decode;
+mod duplicates;
+mod hash;
 mod structs;
+mod thumbnails;
 
 //Texture parameters that the images will all use
 const DEFAULT_TEX_PARAMS: [(GLenum, GLenum); 4] = [
@@ -28,6 +35,92 @@ const DEFAULT_TEX_PARAMS: [(GLenum, GLenum); 4] = [
     (gl::TEXTURE_MAG_FILTER, gl::LINEAR)
 ];
 
+//How long each frame may spend uploading images that the loader pool has finished
+const UPLOAD_TIME_BUDGET: Duration = Duration::from_millis(8);
+
+//Uploads RGBA pixels to a new sRGB texture with DEFAULT_TEX_PARAMS
+unsafe fn upload_rgba_texture(width: u32, height: u32, data: &[u8]) -> GLuint {
+    let mut tex = 0;
+    gl::GenTextures(1, &mut tex);
+    gl::BindTexture(gl::TEXTURE_2D, tex);
+    glutil::apply_texture_parameters(gl::TEXTURE_2D, &DEFAULT_TEX_PARAMS);
+    gl::TexImage2D(gl::TEXTURE_2D, 0, gl::SRGB8_ALPHA8 as GLsizei, width as GLsizei, height as GLsizei, 0, gl::RGBA, gl::UNSIGNED_BYTE, data.as_ptr() as _);
+    tex
+}
+
+//A file's modification time as a unix timestamp
+fn modified_time(file: &fs::Metadata) -> Option<i64> {
+    file.modified().ok().and_then(|t| t.duration_since(UNIX_EPOCH).ok()).map(|d| d.as_secs() as i64)
+}
+
+//Reads, decodes and hashes the image at path at full resolution. This runs on a loader pool thread
+fn load_image(path: &str) -> Result<LoadedImage, String> {
+    let bytes = fs::read(path).map_err(|e| e.to_string())?;
+    let (format, frames) = decode::decode(&bytes)?;
+    let pixels = &frames[0].pixels;
+
+    let metadata = ImageMetadata {
+        width: pixels.width(),
+        height: pixels.height(),
+        size: bytes.len() as u64,
+        format: format!("{:?}", format).to_lowercase(),
+        modified: fs::metadata(path).ok().and_then(|m| modified_time(&m))
+    };
+
+    Ok(LoadedImage {
+        hash: hash::sha256_bytes(&bytes),
+        phash: Some(duplicates::dhash(pixels)),
+        metadata,
+        frames,
+        cached: false
+    })
+}
+
+//Services one request from the main thread. This runs on a loader pool thread
+fn service_request(request: &LoadRequest) -> Result<LoadedImage, String> {
+    match request.kind {
+        LoadKind::Thumbnail | LoadKind::Reload => {
+            //A cached thumbnail means the original doesn't even have to be read
+            //It still has to exist though, or rows for deleted files would never be noticed
+            //Without recorded metadata the original is decoded anyway, so that the metadata gets filled in
+            if let (Some(hash), Some(metadata)) = (&request.hash, &request.metadata) {
+                let file = match fs::metadata(&request.path) {
+                    Ok(file) => { file }
+                    Err(_) => { return Err(String::from("file not found")); }
+                };
+
+                //A file that was replaced since its thumbnail was made has to be decoded and hashed again to find out what it is now
+                let unchanged = file.len() == metadata.size && modified_time(&file) == metadata.modified;
+
+                //Cached thumbnails are single frames, so animations always have to be decoded again
+                let cached = match unchanged && !animation::is_animated(&request.path) {
+                    true => { thumbnails::load_cached(&request.thumbnail_dir, hash) }
+                    false => { None }
+                };
+                if let Some(pixels) = cached {
+                    return Ok(LoadedImage {
+                        frames: vec![Frame { pixels, delay: 0.0 }],
+                        hash: hash.clone(),
+                        phash: None,
+                        metadata: metadata.clone(),
+                        cached: true
+                    });
+                }
+            }
+
+            let mut loaded = load_image(&request.path)?;
+            for frame in loaded.frames.iter_mut() {
+                frame.pixels = thumbnails::make_thumbnail(&frame.pixels);
+            }
+            if let Err(e) = thumbnails::save(&request.thumbnail_dir, &loaded.hash, &loaded.frames[0].pixels) {
+                println!("Error caching thumbnail of {}: {}", request.path, e);
+            }
+            Ok(loaded)
+        }
+        LoadKind::Original | LoadKind::Slide => { load_image(&request.path) }
+    }
+}
+
 fn imstr_ref_array(strs: &Vec<ImString>) -> Vec<&ImString> {    
     let mut tag_refs = Vec::with_capacity(strs.len());
     for t in strs {
@@ -43,23 +136,250 @@ fn insert_tag(strs: &mut Vec<ImString>, new_str: &ImString) {
     }
 }
 
-fn send_or_error<T>(tx: &mpsc::Sender<T>, item: T) {
-    if let Err(e) = tx.send(item) {
-        println!("{}", e);
-    }
-}
-
 fn recompute_selected_tags(selected_image_tags: &mut Vec<bool>, tags: &Vec<ImString>, image_tags: &Vec<ImString>) {    
     for i in 0..tags.len() {
         selected_image_tags[i] = image_tags.contains(&tags[i]);
     }
 }
 
-fn clear_open_images(images: &mut Vec<OpenImage>, selected_image: &mut Option<usize>) {
+//Also cancels every outstanding load, as nothing that was queued belongs to the gallery anymore
+fn clear_open_images(images: &mut Vec<OpenImage>, selected_image: &mut Option<usize>, failed_images: &mut Vec<FailedImage>, loader_pool: &mut LoaderPool) {
+    loader_pool.cancel_all();
+    failed_images.clear();
     *selected_image = None;
     images.clear();                
 }
 
+//Turns the open image at index i into an error tile, for when its file has gone bad since it was opened
+fn fail_open_image(images: &mut Vec<OpenImage>, selected_image: &mut Option<usize>, failed_images: &mut Vec<FailedImage>, i: usize, path: String, reason: String) {
+    let im = images.remove(i);
+    *selected_image = match *selected_image {
+        Some(s) if s == i => { None }
+        Some(s) if s > i => { Some(s - 1) }
+        s => { s }
+    };
+    failed_images.push(FailedImage {
+        path,
+        name: Some(im.name),
+        hash: Some(im.hash),
+        reason
+    });
+}
+
+//Copies the file at new_path in for the library row stored under name, returning where it ended up
+//The copy is staged next to its destination, which is replaced inside the transaction that repoints the row at the new file's hash
+//so that a failed rename leaves the row as it was
+fn relocate_image(lib: &Library, image_directory: &str, name: &str, new_path: &str) -> Result<String, String> {
+    let hash = hash::sha256_file(new_path).map_err(|e| e.to_string())?;
+    let dest = format!("{}/{}", image_directory, name);
+    let staged = format!("{}.relocating", dest);
+    fs::copy(new_path, &staged).map_err(|e| e.to_string())?;
+
+    let updated = lib.transaction(|lib| -> Result<Option<String>, Box<dyn std::error::Error>> {
+        match lib.image_name_for_hash(&hash)? {
+            Some(other) if other != name => { Ok(Some(other)) }
+            _ => {
+                lib.set_image_hash(name, &hash)?;
+                fs::rename(&staged, &dest)?;
+                Ok(None)
+            }
+        }
+    });
+    let result = match updated {
+        Ok(None) => { Ok(()) }
+        Ok(Some(other)) => { Err(format!("that file is already in the library as {}", other)) }
+        Err(e) => { Err(e.to_string()) }
+    };
+    if result.is_err() {
+        if let Err(e) = fs::remove_file(&staged) {
+            println!("Error deleting {}: {}", staged, e);
+        }
+    }
+    result.map(|_| dest)
+}
+
+//Picks the name an image is stored under in the image directory
+//An image that's already in the library keeps its name. Otherwise the first of "name", "stem (1).ext", "stem (2).ext"...
+//that doesn't belong to a different image is used, so same-named files from different folders never alias each other
+fn library_name(library: &Option<Library>, image_directory: &str, name: &str, hash: &str) -> String {
+    if let Some(lib) = library {
+        match lib.image_name_for_hash(hash) {
+            Ok(Some(existing)) => { return existing; }
+            Ok(None) => {}
+            Err(e) => { println!("Error looking up image {}: {}", hash, e); }
+        }
+    }
+
+    let p = Path::new(name);
+    let stem = p.file_stem().and_then(|s| s.to_str()).unwrap_or(name);
+    let extension = p.extension().and_then(|e| e.to_str());
+    let mut n = 0;
+    loop {
+        let candidate = match (n, extension) {
+            (0, _) => { String::from(name) }
+            (_, Some(ext)) => { format!("{} ({}).{}", stem, n, ext) }
+            (_, None) => { format!("{} ({})", stem, n) }
+        };
+
+        //If there's already a file with this name it has to be this exact image
+        let file_path = format!("{}/{}", image_directory, candidate);
+        let file_exists = Path::new(&file_path).exists();
+        let file_matches = file_exists && hash::sha256_file(&file_path).map(|h| h == hash).unwrap_or(false);
+
+        //Same goes for a row with this name. Rows from before hashing are judged by their file
+        let row_free = match library {
+            Some(lib) => {
+                match lib.image_hash_for_name(&candidate) {
+                    Ok(None) => { true }
+                    Ok(Some(Some(h))) => { h == hash }
+                    Ok(Some(None)) => { file_matches }
+                    Err(e) => {
+                        println!("Error looking up image {}: {}", candidate, e);
+                        true
+                    }
+                }
+            }
+            None => { true }
+        };
+
+        if (!file_exists || file_matches) && row_free {
+            return candidate;
+        }
+        n += 1;
+    }
+}
+
+//Seed for a fresh shuffle
+fn new_shuffle_seed() -> i32 {
+    let nanos = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.subsec_nanos()).unwrap_or(0);
+    (nanos % i32::MAX as u32) as i32
+}
+
+//Fisher-Yates shuffle driven by a linear congruential generator, which is plenty for ordering a slideshow
+fn shuffle<T>(items: &mut [T], seed: i32) {
+    let mut state = seed as u32;
+    for i in (1..items.len()).rev() {
+        state = state.wrapping_mul(1664525).wrapping_add(1013904223);
+        items.swap(i, state as usize % (i + 1));
+    }
+}
+
+//Opens the library at path, reporting any failure to the user
+fn open_library(path: &str) -> Option<Library> {
+    match Library::open(path) {
+        Ok(lib) => {
+            if lib.migration.fixed_rows > 0 {
+                tfd::message_box_ok("Database upgraded", &format!("Upgrading {} from schema version {} removed {} duplicate or orphaned tag relationships.", path, lib.migration.from_version, lib.migration.fixed_rows), MessageBoxIcon::Info);
+            }
+            Some(lib)
+        }
+        Err(e) => {
+            tfd::message_box_ok("Error opening database", &format!("Unable to open {}:\n{}", path, e), MessageBoxIcon::Error);
+            None
+        }
+    }
+}
+
+//Fetches every tag from the library as an ImString
+fn fetch_tags(library: &Option<Library>) -> Vec<ImString> {
+    match library {
+        Some(lib) => {
+            match lib.all_tags() {
+                Ok(ts) => { ts.into_iter().map(|t| t.into()).collect() }
+                Err(e) => {
+                    println!("Error fetching tags: {}", e);
+                    Vec::new()
+                }
+            }
+        }
+        None => { Vec::new() }
+    }
+}
+
+//Points the viewer back at its image if open_images has changed under it, closing it if the image is gone
+fn follow_viewer(viewer: &mut Option<Viewer>, open_images: &[OpenImage]) {
+    if let Some(v) = viewer {
+        if open_images.get(v.index).map(|im| &im.hash) != Some(&v.hash) {
+            match open_images.iter().position(|im| im.hash == v.hash) {
+                Some(i) => { v.index = i; }
+                None => { *viewer = None; }
+            }
+        }
+    }
+}
+
+//Rereads one image's tags, as applying a tag can also apply the tags it implies
+fn refresh_image_tags(lib: &Library, im: &mut OpenImage) {
+    match lib.tags_for_image(&im.hash) {
+        Ok(ts) => { im.tags = ts.into_iter().map(|t| t.into()).collect(); }
+        Err(e) => { println!("Error fetching tags for {}: {}", im.name, e); }
+    }
+}
+
+//Rereads the tag list and every open image's tags after a change that touched many images at once
+fn reload_all_tags(library: &Option<Library>, tags: &mut Vec<ImString>, selected_image_tags: &mut Vec<bool>, open_images: &mut [OpenImage], selected_index: Option<usize>) {
+    *tags = fetch_tags(library);
+    if let Some(lib) = library {
+        for im in open_images.iter_mut() {
+            refresh_image_tags(lib, im);
+        }
+    }
+
+    *selected_image_tags = vec![false; tags.len()];
+    if let Some(i) = selected_index {
+        recompute_selected_tags(selected_image_tags, tags, &open_images[i].tags);
+    }
+}
+
+//Deletes the cached thumbnail of an image once the library has no row left with its hash
+fn forget_thumbnail(lib: &Library, thumbnail_dir: &str, hash: &str) {
+    match lib.image_name_for_hash(hash) {
+        Ok(None) => {
+            if let Err(e) = thumbnails::remove(thumbnail_dir, hash) {
+                println!("Error deleting the thumbnail of {}: {}", hash, e);
+            }
+        }
+        Ok(Some(_)) => {}
+        Err(e) => { println!("Error looking up image {}: {}", hash, e); }
+    }
+}
+
+//Shows why a relation between tags was refused, passing database errors on to the caller
+fn relation_result(result: Result<(), TagRelationError>) -> sqlite::Result<bool> {
+    match result {
+        Ok(_) => { Ok(true) }
+        Err(TagRelationError::Sqlite(e)) => { Err(e) }
+        Err(e) => {
+            tfd::message_box_ok("Can't relate these tags", &e.to_string(), MessageBoxIcon::Info);
+            Ok(false)
+        }
+    }
+}
+
+//Same as relation_result, for names refused because they clash with a tag or an alias
+fn name_result(result: Result<(), TagNameError>) -> sqlite::Result<bool> {
+    match result {
+        Ok(_) => { Ok(true) }
+        Err(TagNameError::Sqlite(e)) => { Err(e) }
+        Err(e) => {
+            tfd::message_box_ok("Name already in use", &e.to_string(), MessageBoxIcon::Info);
+            Ok(false)
+        }
+    }
+}
+
+//Queues every image returned by a library query, relative to the image directory
+fn queue_paths(loader_pool: &mut LoaderPool, image_directory: &str, result: sqlite::Result<Vec<ImageRow>>) {
+    match result {
+        Ok(rows) => {
+            for row in rows {
+                loader_pool.queue_image(format!("{}/{}", image_directory, row.name), row.hash, row.metadata);
+            }
+        }
+        Err(e) => { println!("Error querying images: {}", e); }
+    }
+}
+
 fn main() {
     let mut window_size = glm::vec2(1280, 720);
     let mut image_directory = String::from("E:/images/good");
@@ -142,6 +462,7 @@ fn main() {
         io.key_map[imgui::Key::Backspace as usize] = Key::Backspace as u32;
         io.key_map[imgui::Key::Space as usize] = Key::Space as u32;
         io.key_map[imgui::Key::Enter as usize] = Key::Enter as u32;
+        io.key_map[imgui::Key::Escape as usize] = Key::Escape as u32;
         io.key_map[imgui::Key::KeyPadEnter as usize] = Key::KpEnter as u32;
         io.key_map[imgui::Key::A as usize] = Key::A as u32;
         io.key_map[imgui::Key::C as usize] = Key::C as u32;
@@ -177,7 +498,7 @@ fn main() {
     }
 
     
-    let mut connection: Option<sqlite::Connection> = None; //Connection to the database    
+    let mut library: Option<Library> = None;                        //Connection to the database
     let mut tags = Vec::new(); //Fetch tags from database
     let mut selected_image_tags = vec![false; tags.len()];          //An array of which tags are selected for the selected image. Indexed by alphabetical order
 
@@ -193,18 +514,61 @@ fn main() {
     let mut auto_scroll_speed = 200.0;
     
     let mut selected_index = None;                                  //Index into open_images of which image is currently selected or None
+    let mut viewer: Option<Viewer> = None;                          //The full size viewer window, if it's open
+    let mut multi_selection: HashSet<String> = HashSet::new();      //Hashes of the images selected with Ctrl/Shift-click or the rubber band
+    let mut selection_anchor: Option<String> = None;                //Hash of the image that Shift-click ranges start from
+    let mut rubber_band = None;                                     //Where the rubber band drag started in the grid's content area
+    let mut slideshow: Option<Slideshow> = None;                    //The running slideshow, if there is one
+    let mut slide_interval = 5.0;                                   //Seconds each slide is shown for
+    let mut slide_fade = 1.0;                                       //Seconds the crossfade between slides takes
+    let mut slide_shuffle = false;                                  //Whether slides are shown in a random order instead of the gallery's
+    let mut slide_fullscreen = true;                                //Whether the window goes fullscreen while the slideshow runs
+    let mut windowed_rect = None;                                   //Position and size of the window from before it went fullscreen
+    let mut failed_images: Vec<FailedImage> = vec![];               //Grid entries that couldn't be loaded
+    let mut selected_failure = None;                                //Index into failed_images of the error tile that was clicked
+
+    let mut search_buffer = String::with_capacity(256);             //Buffer for the search query input box
+    let mut search_error: Option<String> = None;                    //Why the last search query failed to parse
+    let mut current_view: Option<View> = None;                      //The query that filled the gallery, if any
+    let mut refresh_view = false;                                   //Set to reload the gallery from current_view at the end of the side panel
+    let mut page_size = 100;                                        //How many images are queued each time the gallery is scrolled to the bottom
+    let mut texture_budget_mb = 512;                                //Least recently displayed textures are evicted to stay under this
+    let mut texture_bytes_used = 0;                                 //Texture memory used by open_images as of the last eviction pass
+    let mut sort_key_index = SortKey::ALL.iter().position(|k| *k == SortKey::Random).unwrap();
+    let mut sort_order = SortOrder {
+        key: SortKey::Random,
+        descending: false,
+        seed: new_shuffle_seed()
+    };
+
+    let mut show_duplicate_finder = false;                          //Whether the duplicate finder window is open
+    let mut duplicate_distance = 6;                                 //Maximum number of differing perceptual hash bits for two images to count as duplicates
+    let mut duplicate_groups: Vec<Vec<ImageFingerprint>> = vec![];  //Result of the last duplicate scan
+    let mut delete_duplicates = false;                              //Whether keeping one duplicate deletes the rest or just closes them
+
+    let mut show_result_tagging = false;                            //Whether the window for tagging the whole result set is open
+    let mut result_tag_buffer = String::with_capacity(256);         //Tag to add to or remove from every result
+    let mut replace_from = 0;                                       //Index into tags of the tag being replaced
+    let mut replace_to_buffer = String::with_capacity(256);         //Tag it's being replaced with
+
+    let mut show_tag_manager = false;                               //Whether the tag management window is open
+    let mut tag_usage: Vec<(String, usize)> = vec![];               //Every tag and how many images have it, as of the last refresh
+    let mut tag_aliases: Vec<(String, String)> = vec![];            //Every alias and the tag it stands for, as of the last refresh
+    let mut tag_implications: Vec<Implication> = vec![];            //Every implication between tags, as of the last refresh
+    let mut tag_parents: Vec<(String, String)> = vec![];            //Every (tag, parent) pair in the hierarchy, as of the last refresh
+    let mut refresh_tag_usage = false;                              //Set to reread tag_usage, tag_aliases and the relations from the library
+    let mut editing_tag: Option<String> = None;                     //The tag being renamed or merged in the tag manager
+    let mut rename_buffer = String::with_capacity(256);             //New name for editing_tag
+    let mut merge_target = 0;                                       //Index into tags of the tag editing_tag would be merged into
+    let mut alias_buffer = String::with_capacity(256);              //Alias being added in the tag manager
+    let mut alias_target = 0;                                       //Index into tags of the tag the new alias stands for
+    let mut implied_target = 0;                                     //Index into tags of the tag editing_tag would imply
+    let mut materialize_implication = true;                         //Whether a new implication writes the implied tag onto images
+    let mut parent_target = 0;                                      //Index into tags of the tag editing_tag would be put under
     
-    //Set up the thread for loading the image data from disk
-    let (path_tx, path_rx) = mpsc::channel();                   //Channel for sending paths to the loader thread
-    let (openimage_tx, openimage_rx) = mpsc::channel();         //Channel for sending image data back to the main thread
-    let mut loader_thread = LoaderThread::new(path_tx);         //Client-side tracking of loader thread data
-    thread::spawn(move || {
-        //recv() is a blocking function, so this is an infinite loop
-        while let Ok(path) = path_rx.recv() {
-            let image_data = glutil::image_data_from_path(&path, glutil::ColorSpace::Gamma);
-            send_or_error(&openimage_tx, (image_data, path));
-        }
-    });
+    //Set up the pool of threads for loading the image data from disk
+    let worker_count = thread::available_parallelism().map(|n| n.get()).unwrap_or(4);
+    let (mut loader_pool, openimage_rx) = LoaderPool::spawn(worker_count, thumbnails::thumbnail_dir(&image_directory));
 
     //Struct of timing data
     let mut frame_timer = ozy::structs::FrameTimer::new();
@@ -254,9 +618,20 @@ fn main() {
                 }
                 WindowEvent::Char(c) => { imgui_io.add_input_character(c); }
                 WindowEvent::FileDrop(file_paths) => {
+                    //Dropped folders contribute the images directly inside of them
+                    let mut paths = Vec::new();
                     for path in file_paths {
-                        let s = String::from(path.to_str().unwrap());
-                        loader_thread.queue_image(s);
+                        match fs::read_dir(&path) {
+                            Ok(entries) => { paths.extend(entries.filter_map(|e| e.ok()).map(|e| e.path())); }
+                            Err(_) => { paths.push(path); }
+                        }
+                    }
+
+                    for path in paths.into_iter().filter(|p| decode::is_supported_path(p)) {
+                        match path.to_str() {
+                            Some(s) => { loader_pool.queue_image(String::from(s), None, None); }
+                            None => { println!("Skipping {:?}: path isn't valid UTF-8", path); }
+                        }
                     }
                 }
                 _ => { println!("Unhandled event: {:?}", event); }
@@ -271,50 +646,158 @@ fn main() {
         //Begin Imgui drawing
         let imgui_ui = imgui_context.frame();
 
-        //Receive an image from the image loading thread
-        if let Ok((image, path)) = openimage_rx.try_recv() {
-            //Create the open image struct
-            let mut open_image = OpenImage::from_imagedata(image, path);
+        //Receive images from the loader pool until this frame's upload budget runs out
+        let upload_start = Instant::now();
+        while upload_start.elapsed() < UPLOAD_TIME_BUDGET {
+            let (request, loaded) = match loader_pool.receive(&openimage_rx) {
+                Some(r) => { r }
+                None => { break; }
+            };
+
+            //Results that were already being decoded when the view changed are thrown away
+            if request.generation != loader_pool.generation() {
+                continue;
+            }
 
-            //Copy this image into IMAGE_DIRECTORY if it isn't already there
-            let new_path = format!("{}/{}", image_directory, open_image.name);
-            if !Path::new(&new_path).exists() {
-                //Copy the file to the new path
-                if let Err(e) = fs::copy(&open_image.orignal_path, &new_path) {
-                    println!("Error migrating {} to {}: {}", open_image.orignal_path, image_directory, e);
+            let path = request.path;
+            match (request.kind, loaded) {
+                (LoadKind::Original, Ok(loaded)) => {
+                    //Only keep the full resolution image if it's still wanted
+                    if let Some(im) = open_images.iter_mut().find(|im| im.hash == loaded.hash && im.original_requested) {
+                        im.set_original(&loaded.frames);
+                    }
                 }
-            }
-            
-            if let Some(con) = &connection {
-                //Insert this image into the database if it doesn't already exist
-                while let Err(e) = con.execute(format!(
-                    "
-                    INSERT OR IGNORE INTO images (path) VALUES (\"{}\");
-                    ", open_image.name
-                )) {
-                    println!("{}", e);
+                (LoadKind::Reload, Ok(loaded)) => {
+                    if let Some(i) = open_images.iter().position(|im| Some(&im.hash) == request.hash.as_ref() && im.thumbnail_requested) {
+                        //A file that was replaced since the image was opened isn't this image anymore
+                        if loaded.hash == open_images[i].hash {
+                            open_images[i].set_thumbnail(&loaded.frames);
+
+                            //The file is the same but its size or modification time no longer matched, so record them again
+                            //so that its cached thumbnail can be used next time
+                            if !loaded.cached {
+                                if let Some(lib) = &library {
+                                    if let Err(e) = lib.set_image_metadata(&loaded.hash, &loaded.metadata) {
+                                        println!("Error saving metadata of {}: {}", open_images[i].name, e);
+                                    }
+                                }
+                                open_images[i].metadata = loaded.metadata;
+                            }
+                        } else {
+                            fail_open_image(&mut open_images, &mut selected_index, &mut failed_images, i, path, String::from("the file has changed since it was opened"));
+                        }
+                    }
+                }
+                (LoadKind::Slide, Ok(loaded)) => {
+                    if let Some(show) = &mut slideshow {
+                        show.receive(&path, &loaded.frames);
+                    }
                 }
+                (LoadKind::Slide, Err(e)) => {
+                    println!("Error loading {}: {}", path, e);
+                    if let Some(show) = &mut slideshow {
+                        show.fail(&path);
+                    }
+                }
+                (LoadKind::Original, Err(e)) => { println!("Error loading {}: {}", path, e); }
+                (LoadKind::Reload, Err(e)) => {
+                    println!("Error loading {}: {}", path, e);
+
+                    //The file has gone bad since the image was opened, so its blank grid slot becomes an error tile instead
+                    if let Some(i) = open_images.iter().position(|im| Some(&im.hash) == request.hash.as_ref() && im.thumbnail_requested) {
+                        fail_open_image(&mut open_images, &mut selected_index, &mut failed_images, i, path, e);
+                    }
+                }
+                (LoadKind::Thumbnail, Ok(loaded)) => {
+                    //A row from before hashing whose file is a copy of an already hashed image gets folded into that image's row,
+                    //as otherwise its tags would be stranded on a row that can never be hashed
+                    if let (Some(lib), Some(name)) = (&library, path.strip_prefix(&format!("{}/", image_directory))) {
+                        if let Err(e) = lib.merge_unhashed_duplicate(name, &loaded.hash) {
+                            println!("Error merging {} into its duplicate: {}", name, e);
+                        }
+                    }
 
-                //Retrieve all tags for this image from the DB
-                let mut tag_statement = con.prepare("
-                    SELECT name FROM tags
-                    JOIN
-                    (SELECT tag_id FROM image_tags
-                    WHERE image_tags.image_id = (
-                            SELECT id FROM images WHERE path=?
-                        ))
-                    WHERE id=tag_id ORDER BY name;
-                ").unwrap();
-                tag_statement.bind(1, &*open_image.name).unwrap();
+                    //Create the open image struct
+                    let mut open_image = OpenImage::from_frames(&loaded.frames, path, loaded.hash, loaded.metadata);
+                    open_image.name = library_name(&library, &image_directory, &open_image.name, &open_image.hash);
 
-                //Push each tag into the image's tags array
-                while let State::Row = tag_statement.next().unwrap() {
-                    open_image.tags.push(tag_statement.read::<String>(0).unwrap().into());
+                    //Copy this image into IMAGE_DIRECTORY if it isn't already there
+                    let new_path = format!("{}/{}", image_directory, open_image.name);
+                    if !Path::new(&new_path).exists() {
+                        //Copy the file to the new path
+                        if let Err(e) = fs::copy(&open_image.orignal_path, &new_path) {
+                            println!("Error migrating {} to {}: {}", open_image.orignal_path, image_directory, e);
+                        }
+                    }
+                
+                    if let Some(lib) = &library {
+                        //Insert this image into the database if it doesn't already exist
+                        if let Err(e) = lib.register_image(&open_image.name, &open_image.hash) {
+                            println!("Error adding {} to the database: {}", open_image.name, e);
+                        }
+                        if let Some(phash) = loaded.phash {
+                            if let Err(e) = lib.set_image_phash(&open_image.hash, phash) {
+                                println!("Error saving perceptual hash of {}: {}", open_image.name, e);
+                            }
+                        }
+                        //A cached thumbnail's metadata came from the library in the first place
+                        if !loaded.cached {
+                            if let Err(e) = lib.set_image_metadata(&open_image.hash, &open_image.metadata) {
+                                println!("Error saving metadata of {}: {}", open_image.name, e);
+                            }
+                        }
+
+                        //Retrieve all tags for this image from the DB
+                        match lib.tags_for_image(&open_image.hash) {
+                            Ok(ts) => { open_image.tags = ts.into_iter().map(|t| t.into()).collect(); }
+                            Err(e) => { println!("Error fetching tags for {}: {}", open_image.name, e); }
+                        }
+                    }
+
+                    open_images.push(open_image);
+                    loader_pool.images_in_flight -= 1;
+                }
+                (LoadKind::Thumbnail, Err(e)) => {
+                    println!("Error loading {}: {}", path, e);
+
+                    //Files in the image directory may be backing a library row that the user will want to clean up
+                    let (name, hash) = match (&library, path.strip_prefix(&format!("{}/", image_directory))) {
+                        (Some(lib), Some(name)) => {
+                            match lib.image_hash_for_name(name) {
+                                Ok(Some(hash)) => { (Some(String::from(name)), hash) }
+                                Ok(None) => { (None, None) }
+                                Err(e) => {
+                                    println!("Error looking up {}: {}", name, e);
+                                    (None, None)
+                                }
+                            }
+                        }
+                        _ => { (None, None) }
+                    };
+
+                    failed_images.push(FailedImage {
+                        path,
+                        name,
+                        hash,
+                        reason: e
+                    });
+                    loader_pool.images_in_flight -= 1;
                 }
             }
+        }
+
+        //The viewer follows its image if open_images has changed since last frame, and closes if it's gone
+        follow_viewer(&mut viewer, &open_images);
+        let viewer_index = viewer.as_ref().map(|v| v.index);
 
-            open_images.push(open_image);
-            loader_thread.images_in_flight -= 1;
+        //Only the selected and viewed images keep their full resolution textures
+        for (i, im) in open_images.iter_mut().enumerate() {
+            if Some(i) != selected_index && Some(i) != viewer_index && im.original_requested {
+                im.release_original();
+            }
+            if !im.paused {
+                im.animation_time += frame_timer.delta_time;
+            }
         }
 
         //Draw main window where images are displayed
@@ -347,67 +830,31 @@ fn main() {
                 if let Some(file_token) = imgui_ui.begin_menu("File") {
                     if MenuItem::new("New database").build(&imgui_ui) {
                         if let Some(dir_path) = tfd::select_folder_dialog("Image location", ".") {
-                            tags.clear();
                             image_directory = dir_path;
-                            connection =  {
-                                let con = sqlite::open(format!("{}/images.db", image_directory)).unwrap();
-                                
-                                //Initialize the tables
-                                con.execute(
-                                    "
-                                    CREATE TABLE images (id INTEGER, path STRING NOT NULL UNIQUE, PRIMARY KEY (id));
-                                    CREATE TABLE tags (id INTEGER, name STRING NOT NULL UNIQUE, PRIMARY KEY (id));
-                                    CREATE TABLE image_tags (image_id INTEGER, tag_id INTEGER);
-                                    "
-                                ).unwrap();
-
-                                Some(con)
-                            };
+                            loader_pool.thumbnail_dir = thumbnails::thumbnail_dir(&image_directory);
+                            library = open_library(&format!("{}/images.db", image_directory));
+                            tags = fetch_tags(&library);
+                            selected_image_tags = vec![false; tags.len()];
+
+                            //Nothing in the gallery belongs to the new library
+                            clear_open_images(&mut open_images, &mut selected_index, &mut failed_images, &mut loader_pool);
+                            current_view = None;
+                            duplicate_groups.clear();
                         }
                     }
 
                     if MenuItem::new("Open database").build(&imgui_ui) {
                         if let Some(db_path) = tfd::open_file_dialog("Open database", "", Some((&["*.db"], "database"))) {
                             image_directory = String::from(Path::new(&db_path).parent().unwrap().to_str().unwrap());
+                            loader_pool.thumbnail_dir = thumbnails::thumbnail_dir(&image_directory);
 
-                            connection = if !Path::new(&db_path).exists() {
-                                let con = sqlite::open(db_path).unwrap();
-                                
-                                //Initialize the tables
-                                con.execute(
-                                    "
-                                    CREATE TABLE images (id INTEGER, path STRING NOT NULL UNIQUE, PRIMARY KEY (id));
-                                    CREATE TABLE tags (id INTEGER, name STRING NOT NULL UNIQUE, PRIMARY KEY (id));
-                                    CREATE TABLE image_tags (image_id INTEGER, tag_id INTEGER);
-                                    "
-                                ).unwrap();
-                                
-                                Some(con)
-                            } else { Some(sqlite::open(db_path).unwrap()) };
-
-                            //Fetch tags from database
-                            tags = match &connection {
-                                Some(con) => {
-                                    let mut tag_statement = con.prepare(
-                                        "
-                                        SELECT name FROM tags ORDER BY name;
-                                        "
-                                    ).unwrap();
-
-                                    //Convert to imgui::ImString
-                                    let mut ts = Vec::new();
-                                    while let State::Row = tag_statement.next().unwrap() {
-                                        let the_string = tag_statement.read::<String>(0).unwrap();
-                                        ts.push(the_string.into());
-                                    }
-                                    ts
-                                }
-                                None => {
-                                    Vec::new()
-                                }
-                            };
-
+                            library = open_library(&db_path);
+                            tags = fetch_tags(&library);
                             selected_image_tags = vec![false; tags.len()];
+
+                            clear_open_images(&mut open_images, &mut selected_index, &mut failed_images, &mut loader_pool);
+                            current_view = None;
+                            duplicate_groups.clear();
                         }
                     }
 
@@ -418,46 +865,222 @@ fn main() {
                     file_token.end();
                 }
 
+                if let Some(tools_token) = imgui_ui.begin_menu("Tools") {
+                    if MenuItem::new("Find duplicates").build(&imgui_ui) {
+                        show_duplicate_finder = true;
+                    }
+
+                    if MenuItem::new("Tag search results").build(&imgui_ui) {
+                        show_result_tagging = true;
+                    }
+
+                    if MenuItem::new("Manage tags").build(&imgui_ui) {
+                        show_tag_manager = true;
+                        refresh_tag_usage = true;
+                    }
+
+                    tools_token.end();
+                }
+
                 menu_token.end();
             }
 
             //Drawing each open_image as an imgui::ImageButton
+            //The grid is virtualized: rows far from the viewport are empty space and give up their textures,
+            //and rows coming back into range get their thumbnails reloaded
             let max_width = (window_size.x as f32 - side_panel_width) / pics_per_row as f32 - 24.0;
-            for i in 0..open_images.len() {
-                let im = &open_images[i];
-                let factor = max_width as f32 / im.width as f32;
-
-                //Color the selected image
-                let tint_color = match selected_index {
-                    Some(idx) => {
-                        if i == idx {
+            let style = imgui_ui.clone_style();
+            let frame_padding = style.frame_padding;
+            let view_top = imgui_ui.scroll_y();
+            let view_height = window_size.y as f32;
+            let mut grid_rects = Vec::with_capacity(open_images.len());     //Where each image is in the grid's content area as [x0, y0, x1, y1]
+            let mut near_viewport = Vec::with_capacity(open_images.len());  //Whether each image is on screen or close enough to be prefetched
+            let texture_budget = texture_budget_mb * 1024 * 1024;
+            texture_bytes_used = open_images.iter().map(|im| im.texture_bytes()).sum();
+            let mut grid_click = None;
+            let mut row_start = 0;
+            while row_start < open_images.len() {
+                let row_end = usize::min(row_start + pics_per_row as usize, open_images.len());
+                let row_height = open_images[row_start..row_end].iter()
+                                 .map(|im| im.height as f32 * max_width / im.width as f32)
+                                 .fold(0.0, f32::max) + 2.0 * frame_padding[1];
+
+                //How far this row is from being on screen
+                let row_top = imgui_ui.cursor_pos()[1];
+                let distance = if row_top + row_height < view_top {
+                    view_top - (row_top + row_height)
+                } else if row_top > view_top + view_height {
+                    row_top - (view_top + view_height)
+                } else {
+                    0.0
+                };
+
+                //Every button in the grid is max_width wide, so the layout can be worked out even for rows that aren't drawn
+                let row_left = imgui_ui.cursor_pos()[0];
+                for (column, im) in open_images[row_start..row_end].iter().enumerate() {
+                    let left = row_left + column as f32 * (max_width + 2.0 * frame_padding[0] + style.item_spacing[0]);
+                    let height = im.height as f32 * max_width / im.width as f32 + 2.0 * frame_padding[1];
+                    grid_rects.push([left, row_top, left + max_width + 2.0 * frame_padding[0], row_top + height]);
+                    near_viewport.push(distance <= view_height);
+                }
+
+                //Rows just off screen are prefetched, but only while there's room in the texture budget
+                let prefetch = distance == 0.0 || texture_bytes_used < texture_budget;
+                for im in open_images[row_start..row_end].iter_mut() {
+                    if distance > 2.0 * view_height {
+                        im.release_thumbnail();
+                    } else if distance <= view_height && prefetch && im.gl_name.is_none() && !im.thumbnail_requested {
+                        let priority = if distance == 0.0 { PRIORITY_VISIBLE } else { PRIORITY_PREFETCH };
+                        im.thumbnail_requested = true;
+                        loader_pool.queue_reload(format!("{}/{}", image_directory, im.name), im.hash.clone(), im.metadata.clone(), priority);
+                    }
+                }
+
+                if distance > 0.0 {
+                    imgui_ui.dummy([max_width, row_height]);
+                    row_start = row_end;
+                    continue;
+                }
+
+                for i in row_start..row_end {
+                    let im = &mut open_images[i];
+                    let factor = max_width as f32 / im.width as f32;
+                    im.last_displayed = frame_timer.elapsed_time;
+
+                    //Color the selected image, and tint the rest of the multi-selection
+                    let tint_color = match selected_index {
+                        Some(idx) if i == idx => {
                             let time = frame_timer.elapsed_time - time_selected;
                             let func = 0.5 * f32::cos(6.0 * time) + 0.5;
                             [1.0, 1.0, func, 1.0]
-                        } else { 
-                            [1.0, 1.0, 1.0, 1.0]
                         }
+                        _ => {
+                            if multi_selection.contains(&im.hash) {
+                                [0.6, 0.8, 1.0, 1.0]
+                            } else {
+                                [1.0, 1.0, 1.0, 1.0]
+                            }
+                        }
+                    };
+
+                    let size = [im.width as f32 * factor, im.height as f32 * factor];
+                    let clicked = match im.current_frame(false) {
+                        Some(tex) => {
+                            ImageButton::new(TextureId::new(tex as usize), size)
+                            .tint_col(tint_color)
+                            .build(&imgui_ui)
+                        }
+
+                        //Placeholder of the same size while the thumbnail is on its way back
+                        None => { imgui_ui.button_with_size(format!("###placeholder{}", i), [size[0] + 2.0 * frame_padding[0], size[1] + 2.0 * frame_padding[1]]) }
+                    };
+
+                    if clicked {
+                        grid_click = Some(i);
                     }
-                    None => {
-                        [1.0, 1.0, 1.0, 1.0]
+                    if i + 1 < row_end {
+                        imgui_ui.same_line();
                     }
-                };
+                }
+                row_start = row_end;
+            }
 
-                if ImageButton::new(TextureId::new(im.gl_name as usize),
-									[im.width as f32 * factor, im.height as f32 * factor])
-									.tint_col(tint_color)
-									.build(&imgui_ui) {
+            //Plain clicks open the control panel, Ctrl toggles images in and out of the selection and Shift selects ranges
+            if let Some(i) = grid_click {
+                let (ctrl, shift) = (imgui_ui.io().key_ctrl, imgui_ui.io().key_shift);
+                if shift {
+                    if !ctrl {
+                        multi_selection.clear();
+                    }
+                    //An anchor that has been closed since it was clicked starts the range at the clicked image instead
+                    let anchor = selection_anchor.as_ref().and_then(|hash| open_images.iter().position(|im| im.hash == *hash)).unwrap_or(i);
+                    for im in &open_images[usize::min(anchor, i)..=usize::max(anchor, i)] {
+                        multi_selection.insert(im.hash.clone());
+                    }
+                } else if ctrl {
+                    if !multi_selection.remove(&open_images[i].hash) {
+                        multi_selection.insert(open_images[i].hash.clone());
+                    }
+                    selection_anchor = Some(open_images[i].hash.clone());
+                } else {
+                    multi_selection.clear();
+                    multi_selection.insert(open_images[i].hash.clone());
+                    selection_anchor = Some(open_images[i].hash.clone());
                     selected_index = Some(i);
                     time_selected = frame_timer.elapsed_time;
 
                     //Compute selected_image_tags
-                    recompute_selected_tags(&mut selected_image_tags, &tags, &im.tags);
+                    recompute_selected_tags(&mut selected_image_tags, &tags, &open_images[i].tags);
                 }
-                imgui_ui.same_line();
-                if i as u32 % pics_per_row == pics_per_row - 1 {
-                    imgui_ui.new_line();
+            }
+
+            //Rubber band selection, started by dragging over empty space in the grid
+            //The band is kept in content coordinates so that it stays anchored if the grid scrolls while dragging
+            let window_pos = imgui_ui.window_pos();
+            let mouse_pos = imgui_ui.io().mouse_pos;
+            let mouse_content = [mouse_pos[0] - window_pos[0] + imgui_ui.scroll_x(), mouse_pos[1] - window_pos[1] + imgui_ui.scroll_y()];
+            let in_grid = mouse_pos[0] - window_pos[0] < window_size.x as f32 - side_panel_width;
+            if rubber_band.is_none() && in_grid && imgui_ui.is_window_hovered() && !imgui_ui.is_any_item_hovered() && imgui_ui.is_mouse_clicked(imgui::MouseButton::Left) {
+                rubber_band = Some(mouse_content);
+            }
+            if let Some(start) = rubber_band {
+                let band = [
+                    f32::min(start[0], mouse_content[0]), f32::min(start[1], mouse_content[1]),
+                    f32::max(start[0], mouse_content[0]), f32::max(start[1], mouse_content[1])
+                ];
+
+                if imgui_ui.is_mouse_down(imgui::MouseButton::Left) {
+                    let to_screen = |x: f32, y: f32| [x - imgui_ui.scroll_x() + window_pos[0], y - imgui_ui.scroll_y() + window_pos[1]];
+                    let draw_list = imgui_ui.get_window_draw_list();
+                    draw_list.add_rect(to_screen(band[0], band[1]), to_screen(band[2], band[3]), [0.4, 0.6, 1.0, 0.25]).filled(true).build();
+                    draw_list.add_rect(to_screen(band[0], band[1]), to_screen(band[2], band[3]), [0.4, 0.6, 1.0, 1.0]).build();
+                } else {
+                    //Releasing a band that covers nothing, such as a click on empty space, clears the selection
+                    if !imgui_ui.io().key_ctrl {
+                        multi_selection.clear();
+                    }
+                    for (i, rect) in grid_rects.iter().enumerate() {
+                        if rect[0] < band[2] && rect[2] > band[0] && rect[1] < band[3] && rect[3] > band[1] {
+                            multi_selection.insert(open_images[i].hash.clone());
+                        }
+                    }
+                    rubber_band = None;
+                }
+            }
+
+            //Error tiles for the images that couldn't be loaded
+            for (i, failed) in failed_images.iter().enumerate() {
+                let filename = Path::new(&failed.path).file_name().and_then(|n| n.to_str()).unwrap_or(&failed.path);
+                if imgui_ui.button_with_size(format!("Failed to load\n{}###failed{}", filename, i), [max_width, max_width * 0.5]) {
+                    selected_failure = Some(i);
+                    selected_index = None;
+                }
+                if imgui_ui.is_item_hovered() {
+                    imgui_ui.tooltip_text(&failed.reason);
+                }
+                if (i + 1) % pics_per_row as usize != 0 && i + 1 < failed_images.len() {
+                    imgui_ui.same_line();
+                }
+            }
+
+            //Evict the least recently displayed thumbnails until we're back under budget
+            //Anything on screen or in the prefetch range is safe, as evicting it would only get it loaded straight back in,
+            //so a budget that's too small can't make those rows flicker or reload every frame
+            texture_bytes_used = open_images.iter().map(|im| im.texture_bytes()).sum();
+            if texture_bytes_used > texture_budget {
+                let mut candidates: Vec<usize> = (0..open_images.len()).filter(|&i| {
+                    open_images[i].gl_name.is_some() && !near_viewport[i]
+                }).collect();
+                candidates.sort_by(|&a, &b| open_images[a].last_displayed.partial_cmp(&open_images[b].last_displayed).unwrap());
+
+                for i in candidates {
+                    if texture_bytes_used <= texture_budget {
+                        break;
+                    }
+                    texture_bytes_used -= open_images[i].thumbnail_bytes();
+                    open_images[i].release_thumbnail();
                 }
-            }            
+            }
 
             //Specify side panel
             const Y_PADDING: f32 = 27.0;
@@ -467,46 +1090,32 @@ fn main() {
             
             //Button to spawn a file(s) selection dialogue for loading images
             if imgui_ui.button_with_size("Open image(s)", [0.0, 32.0]) {
-                if let Some(image_paths) = tfd::open_file_dialog_multi("Open image", &image_directory, Some((&["*.png", "*.jpg"], ".png, .jpg"))) {
+                let patterns = decode::dialog_filter();
+                let patterns: Vec<&str> = patterns.iter().map(|p| p.as_str()).collect();
+                if let Some(image_paths) = tfd::open_file_dialog_multi("Open image", &image_directory, Some((&patterns[..], "Images"))) {
                     for path in image_paths {
-                        loader_thread.queue_image(path);
+                        loader_pool.queue_image(path, None, None);
                     }
                 }
             }
 
             if imgui_ui.button_with_size("Load tagless images", [0.0, 32.0]) {
-                match &connection {
-                    Some(con) => {
-                        clear_open_images(&mut open_images, &mut selected_index);
-                        let mut statement = con.prepare("
-                            SELECT path FROM images
-                            JOIN
-                                (SELECT id AS im_id FROM images
-                                EXCEPT
-                                SELECT image_id from image_tags)
-                            WHERE id=im_id ORDER BY random();
-                        ").unwrap();
-
-                        let tagless_loaded = 200;
-                        let mut count = 0;
-                        while let State::Row = statement.next().unwrap() {
-                            if count < tagless_loaded {
-                                let path = format!("{}/{}", image_directory, statement.read::<String>(0).unwrap());
-                                loader_thread.queue_image(path);
-                                count += 1;
-                            }
-                        }
-                    }
-                    None => {
-                        tfd::message_box_ok("No loaded database", "One cannot load images from a database that is not there\n-Kanye", MessageBoxIcon::Error);
-                    }
+                if let Some(_lib) = &library {
+                    //This is just a shortcut for the tagcount:0 search
+                    search_buffer = String::from("tagcount:0");
+                    search_error = None;
+                    current_view = Some(View::new(Some(query::Expr::Compare(query::Field::TagCount, query::Comparison::Equal, sqlite::Value::Integer(0)))));
+                    refresh_view = true;
+                } else {
+                    tfd::message_box_ok("No loaded database", "One cannot load images from a database that is not there\n-Kanye", MessageBoxIcon::Error);
                 }
 
                 
             }
                                 
             if imgui_ui.button_with_size("Close open images", [0.0, 32.0]) {
-                clear_open_images(&mut open_images, &mut selected_index);
+                clear_open_images(&mut open_images, &mut selected_index, &mut failed_images, &mut loader_pool);
+                current_view = None;        //Otherwise the next page of the old view would be loaded straight back in
             }
 
             if imgui_ui.button_with_size("Copy loaded to temp file", [0.0, 32.0]) {
@@ -525,50 +1134,154 @@ fn main() {
 
             imgui_ui.text("Active tag");
             imgui_ui.set_next_item_width(side_panel_width - 50.0);            
-            if loader_thread.images_in_flight == 0 && imgui_ui.combo_simple_string("###Active tag", &mut selected_tag, imstr_ref_array(&tags).as_slice()) {
-                clear_open_images(&mut open_images, &mut selected_index);
-                imgui_ui.set_scroll_y(0.0);
-
-                if let Some(con) = &connection {
-                    let mut statement = con.prepare("
-                        SELECT path FROM images
-                        JOIN
-                        (SELECT image_id FROM image_tags
-                        WHERE image_tags.tag_id = (
-                                SELECT id FROM tags WHERE name=?
-                            ))
-                        WHERE id=image_id ORDER BY random();
-                    ").unwrap();
-                    statement.bind(1, tags[selected_tag].to_str()).unwrap();
-                    while let State::Row = statement.next().unwrap() {
-                        let s = statement.read::<String>(0).unwrap();
-                        let path = format!("{}/{}", image_directory, s);
-                        loader_thread.queue_image(path);
-                    }
-                }
-            }
-            imgui_ui.text(format!("{} images loaded.", open_images.len()));
+            if imgui_ui.combo_simple_string("###Active tag", &mut selected_tag, imstr_ref_array(&tags).as_slice()) {
+                current_view = Some(View::new(Some(query::Expr::Tag(String::from(tags[selected_tag].to_str())))));
+                refresh_view = true;
+            }
+            imgui_ui.text("Search");
+            imgui_ui.set_next_item_width(side_panel_width - 50.0);
+            let search_entered = imgui::InputText::new(&imgui_ui, "###Search", &mut search_buffer).enter_returns_true(true).build();
+            if search_entered || imgui_ui.button_with_size("Search", [0.0, 32.0]) {
+                match query::parse(&search_buffer) {
+                    Ok(expr) => {
+                        search_error = None;
+                        match &library {
+                            Some(_) => {
+                                current_view = Some(View::new(expr));
+                                refresh_view = true;
+                            }
+                            None => {
+                                tfd::message_box_ok("No loaded database", "One cannot search a database that is not there", MessageBoxIcon::Error);
+                            }
+                        }
+                    }
+                    Err(e) => { search_error = Some(e.to_string()); }
+                }
+            }
+            if let Some(e) = &search_error {
+                imgui_ui.text_colored([1.0, 0.3, 0.3, 1.0], e);
+            }
+
+            imgui_ui.text("Sort by");
+            imgui_ui.set_next_item_width(side_panel_width - 50.0);
+            let sort_key_names: Vec<&str> = SortKey::ALL.iter().map(|k| k.name()).collect();
+            if imgui_ui.combo_simple_string("###Sort by", &mut sort_key_index, &sort_key_names) {
+                sort_order.key = SortKey::ALL[sort_key_index];
+                refresh_view = true;
+            }
+            if imgui_ui.checkbox("Descending", &mut sort_order.descending) {
+                refresh_view = true;
+            }
+            if sort_order.key == SortKey::Random {
+                imgui_ui.set_next_item_width(side_panel_width - 50.0);
+                if imgui_ui.input_int("Seed", &mut sort_order.seed).enter_returns_true(true).build() {
+                    refresh_view = true;
+                }
+                if imgui_ui.button_with_size("Reshuffle", [0.0, 32.0]) {
+                    sort_order.seed = new_shuffle_seed();
+                    refresh_view = true;
+                }
+            }
+
+            imgui_ui.text("Texture budget (MB)");
+            imgui_ui.set_next_item_width(side_panel_width - 50.0);
+            imgui::Slider::new("###Texture budget", 64, 4096).build(&imgui_ui, &mut texture_budget_mb);
+            imgui_ui.text(format!("Texture memory: {:.1} MB", texture_bytes_used as f32 / (1024.0 * 1024.0)));
+
+            imgui_ui.text("Page size");
+            imgui_ui.set_next_item_width(side_panel_width - 50.0);
+            imgui::Slider::new("###Page size", 10, 1000).build(&imgui_ui, &mut page_size);
+
+            //Reload the gallery if the query or its order changed
+            if refresh_view {
+                refresh_view = false;
+                if let (Some(lib), Some(view)) = (&library, &mut current_view) {
+                    clear_open_images(&mut open_images, &mut selected_index, &mut failed_images, &mut loader_pool);
+                    imgui_ui.set_scroll_y(0.0);
+
+                    view.loaded = 0;
+                    view.ids = match lib.query_image_ids(&view.expr, &sort_order) {
+                        Ok(ids) => { ids }
+                        Err(e) => {
+                            println!("Error querying images: {}", e);
+                            Vec::new()
+                        }
+                    };
+                }
+            }
+
+            //Queue the next page once the previous one has arrived and the grid is scrolled near the bottom
+            if let (Some(lib), Some(view)) = (&library, &mut current_view) {
+                let near_bottom = imgui_ui.scroll_y() >= imgui_ui.scroll_max_y() - window_size.y as f32;
+                if view.loaded < view.ids.len() && loader_pool.images_in_flight == 0 && near_bottom {
+                    let end = usize::min(view.loaded + page_size, view.ids.len());
+                    let paths = lib.images_with_ids(&view.ids[view.loaded..end]);
+                    view.loaded = end;
+                    queue_paths(&mut loader_pool, &image_directory, paths);
+                }
+            }
+
+            match &current_view {
+                Some(view) => { imgui_ui.text(format!("{} of {} images loaded.", open_images.len(), view.ids.len())); }
+                None => { imgui_ui.text(format!("{} images loaded.", open_images.len())); }
+            }
+            if !failed_images.is_empty() {
+                imgui_ui.text_colored([1.0, 0.3, 0.3, 1.0], format!("{} images failed to load.", failed_images.len()));
+            }
 
             imgui_ui.text("Scroll speed");
             imgui_ui.set_next_item_width(side_panel_width - 50.0);
             imgui::Slider::new("###Scroll speed", 150.0, 750.0).build(&imgui_ui, &mut auto_scroll_speed);
             imgui_ui.checkbox("Auto-scrolling", &mut auto_scroll);
 
-            if let Some(_) = selected_index {
-                
-                if let Some(token) = imgui::Window::new("uwu_db")
-                                     .position([0.0, 0.0], Condition::Always)
-                                     .size([window_size.x as f32, window_size.y as f32], Condition::Always)
-                                     .resizable(true)
-                                     .collapsible(false)
-                                     .title_bar(false)
-                                     .begin(&imgui_ui) {
-                    
-                    //                    
-                    
-                    token.end()
+            imgui_ui.separator();
+            imgui_ui.text("Slide interval (seconds)");
+            imgui_ui.set_next_item_width(side_panel_width - 50.0);
+            imgui::Slider::new("###Slide interval", 1.0, 60.0).build(&imgui_ui, &mut slide_interval);
+            imgui_ui.text("Crossfade (seconds)");
+            imgui_ui.set_next_item_width(side_panel_width - 50.0);
+            imgui::Slider::new("###Crossfade", 0.0, 3.0).build(&imgui_ui, &mut slide_fade);
+            imgui_ui.checkbox("Shuffle slides", &mut slide_shuffle);
+            imgui_ui.checkbox("Fullscreen slideshow", &mut slide_fullscreen);
+            if imgui_ui.button_with_size("Start slideshow", [0.0, 32.0]) {
+                //The slideshow covers the whole query, not just the pages the gallery has loaded so far
+                let mut paths: Vec<String> = match (&library, &current_view) {
+                    (Some(lib), Some(view)) => {
+                        match lib.query_images(&view.expr, &sort_order) {
+                            Ok(rows) => { rows.into_iter().map(|row| format!("{}/{}", image_directory, row.name)).collect() }
+                            Err(e) => {
+                                println!("Error querying images: {}", e);
+                                vec![]
+                            }
+                        }
+                    }
+                    _ => { open_images.iter().map(|im| im.orignal_path.clone()).collect() }
+                };
+                if slide_shuffle {
+                    shuffle(&mut paths, new_shuffle_seed());
+                }
+
+                if paths.is_empty() {
+                    tfd::message_box_ok("Nothing to show", "Load or search for some images before starting a slideshow", MessageBoxIcon::Info);
+                } else {
+                    //Borderless fullscreen: the monitor keeps its current video mode
+                    if slide_fullscreen {
+                        let (x, y) = window.get_pos();
+                        let (w, h) = window.get_size();
+                        windowed_rect = Some((x, y, w, h));
+                        glfw.with_primary_monitor(|_, monitor| {
+                            if let Some(m) = monitor {
+                                if let Some(mode) = m.get_video_mode() {
+                                    window.set_monitor(WindowMode::FullScreen(m), 0, 0, mode.width, mode.height, Some(mode.refresh_rate));
+                                }
+                            }
+                        });
+                    }
+                    auto_scroll = false;
+                    slideshow = Some(Slideshow::new(paths));
                 }
             }
+            imgui_ui.text("Space pauses, arrow keys skip\nand Escape ends the slideshow.");
 
             token.end();
         }
@@ -595,6 +1308,12 @@ fn main() {
             let im = &mut open_images[image_idx];       //Get mutable reference to the selected image
             let mut removing_this = false;            //Flag for if we want to close this image
 
+            //The control panel is the one place that shows the image at full resolution
+            if !im.original_requested {
+                im.original_requested = true;
+                loader_pool.queue_original(im.orignal_path.clone(), im.hash.clone());
+            }
+
             //Create control panel for manipulating the selected image
             if let Some(token) = imgui::Window::new(&format!("{}###control_panel", im.orignal_path))
                                  .collapsible(false)
@@ -618,11 +1337,11 @@ fn main() {
                     //Pop up confirmation dialogue for image deletion
                     if let YesNo::Yes = tfd::message_box_yes_no("Delete this image", &format!("You are about to permanently delete\n{}\nProceed?", im.name), MessageBoxIcon::Warning, YesNo::No) {
                         //Delete the image and its relationships from the database
-                        if let Some(con) = &connection {
-                            con.execute(format!("
-                                DELETE FROM image_tags WHERE image_id=(SELECT id FROM images WHERE path=\"{}\");
-                                DELETE FROM images WHERE path=\"{}\";
-                            ", im.name, im.name)).unwrap();
+                        if let Some(lib) = &library {
+                            match lib.delete_image(&im.hash) {
+                                Ok(_) => { forget_thumbnail(lib, &loader_pool.thumbnail_dir, &im.hash); }
+                                Err(e) => { println!("Error deleting {} from the database: {}", im.name, e); }
+                            }
                         }
                         
                         close_image(&mut removing_this, &mut selected_index);
@@ -644,6 +1363,26 @@ fn main() {
                         println!("{}", e);
                     }
                 }
+                imgui_ui.same_line();
+
+                if imgui_ui.button_with_size("View full size", [0.0, 32.0]) {
+                    viewer = Some(Viewer::new(image_idx, im.hash.clone()));
+                }
+
+                imgui_ui.text(format!("{}x{} {}, {} KB", im.metadata.width, im.metadata.height, im.metadata.format, im.metadata.size / 1024));
+
+                if im.is_animated() {
+                    let label = if im.paused { "Play###play_pause" } else { "Pause###play_pause" };
+                    if imgui_ui.button_with_size(label, [0.0, 32.0]) {
+                        im.paused = !im.paused;
+                    }
+                }
+
+                //Show the thumbnail until the full resolution image arrives
+                let preview_width = f32::min(400.0, im.width as f32);
+                if let Some(preview_texture) = im.current_frame(true) {
+                    imgui::Image::new(TextureId::new(preview_texture as usize), [preview_width, preview_width * im.height as f32 / im.width as f32]).build(&imgui_ui);
+                }
 
                 imgui_ui.separator();
 
@@ -653,32 +1392,25 @@ fn main() {
                     let new_tag = new_tag_buffer.clone();       //Make a copy of the text in the input field
                     new_tag_buffer.clear();                     //Clear the input field                    
 
-                    //Do nothing if this tag already exists
-                    let new_tag = new_tag.into();
-                    if !tags.contains(&new_tag) {
-                        match &connection {
-                            Some(con) => {
-                                //Insert tags into appropriate arrays
-                                insert_tag(&mut tags, &new_tag);
-                                insert_tag(&mut im.tags, &new_tag);
-            
-                                selected_image_tags.push(false);
-                                recompute_selected_tags(&mut selected_image_tags, &tags, &im.tags);
-
-                                //Do SQL
-                                con.execute(format!(
-                                    "
-                                    INSERT OR IGNORE INTO tags (name) VALUES (\"{}\");
-                                    INSERT OR IGNORE INTO images (path) VALUES (\"{}\");
-                                    INSERT OR IGNORE INTO image_tags VALUES (
-                                            (SELECT id FROM images WHERE path=\"{}\")
-                                        ,   (SELECT id FROM tags WHERE name=\"{}\")
-                                        );
-                                    ", new_tag.to_str(), im.name, im.name, new_tag.to_str())
-                                ).unwrap();
+                    //Typing an alias applies the tag it stands for, which may already exist
+                    match &library {
+                        Some(lib) => {
+                            //Do SQL
+                            match lib.add_tag_to_image(&im.name, &im.hash, &new_tag) {
+                                Ok(applied) => {
+                                    //Insert tags into appropriate arrays
+                                    let applied = applied.into();
+                                    if !tags.contains(&applied) {
+                                        insert_tag(&mut tags, &applied);
+                                        selected_image_tags.push(false);
+                                    }
+                                    refresh_image_tags(lib, im);
+                                    recompute_selected_tags(&mut selected_image_tags, &tags, &im.tags);
+                                }
+                                Err(e) => { tfd::message_box_ok("Error creating tag", &format!("Couldn't apply {}: {}", new_tag, e), MessageBoxIcon::Error); }
                             }
-                            None => { tfd::message_box_ok("Saving with no db", "You need to open a database before you can do this", MessageBoxIcon::Error); }
                         }
+                        None => { tfd::message_box_ok("Saving with no db", "You need to open a database before you can do this", MessageBoxIcon::Error); }
                     }
                 }
                 imgui_ui.separator();
@@ -693,16 +1425,16 @@ fn main() {
                 for i in 0..tags.len() {
                     if imgui_ui.checkbox(&tags[i], &mut selected_image_tags[i]) {
                         if selected_image_tags[i] {
-                            match &connection {
-                                Some(con) => {
-                                    con.execute(format!(
-                                        "
-                                        INSERT OR IGNORE INTO image_tags VALUES (
-                                            (SELECT id FROM images WHERE path=\"{}\")
-                                        ,   (SELECT id FROM tags WHERE name=\"{}\")
-                                        );
-                                        ", im.name, tags[i].to_str()
-                                    )).unwrap();
+                            match &library {
+                                Some(lib) => {
+                                    match lib.add_tag_to_image(&im.name, &im.hash, tags[i].to_str()) {
+                                        Ok(_) => {
+                                            //Implied tags get checked too
+                                            refresh_image_tags(lib, im);
+                                            recompute_selected_tags(&mut selected_image_tags, &tags, &im.tags);
+                                        }
+                                        Err(e) => { tfd::message_box_ok("Error applying tag", &format!("Couldn't apply {}: {}", tags[i].to_str(), e), MessageBoxIcon::Error); }
+                                    }
                                 }
                                 None => { tfd::message_box_ok("Saving with no db", "You need to open a database before you can do this", MessageBoxIcon::Error); }
                             }
@@ -721,15 +1453,11 @@ fn main() {
                 //If we're removing a tag from an image
                 if let Some(idx) = to_remove {
                     //Delete the relationship in the database                    
-                    if let Some(con) = &connection {
-                        con.execute(format!("
-                            DELETE FROM image_tags WHERE image_id=(
-                                SELECT id FROM images WHERE path=\"{}\"
-                            ) AND tag_id=(
-                                SELECT id FROM tags WHERE name=\"{}\"
-                            )
-                        ", im.name, im.tags[idx].to_str())).unwrap();
-                        im.tags.remove(idx);
+                    if let Some(lib) = &library {
+                        match lib.remove_tag_from_image(&im.hash, im.tags[idx].to_str()) {
+                            Ok(_) => { im.tags.remove(idx); }
+                            Err(e) => { println!("Error removing tag {}: {}", im.tags[idx].to_str(), e); }
+                        }
                     }
                 }
 
@@ -744,6 +1472,707 @@ fn main() {
             }
         }
 
+        //Batch tag window for the multi-selection
+        let selected_hashes: Vec<String> = open_images.iter().filter(|im| multi_selection.contains(&im.hash)).map(|im| im.hash.clone()).collect();
+        if selected_hashes.len() != multi_selection.len() {
+            multi_selection = selected_hashes.iter().cloned().collect();      //Forget images that have been closed
+        }
+        if selected_hashes.len() > 1 {
+            let count = selected_hashes.len();
+            let mut change = None;
+            if let Some(token) = imgui::Window::new(&format!("{} images selected###batch_tags", count))
+                                 .size([300.0, 400.0], Condition::FirstUseEver)
+                                 .begin(&imgui_ui) {
+
+                if imgui_ui.button_with_size("Clear selection", [0.0, 32.0]) {
+                    multi_selection.clear();
+                }
+                imgui_ui.separator();
+
+                //Checked when every selected image has the tag, and marked with a dash and a count when only some of them do
+                //Checking applies the tag to the whole selection and unchecking removes it from all of them
+                for tag in tags.iter() {
+                    let with_tag = open_images.iter().filter(|im| multi_selection.contains(&im.hash) && im.tags.contains(tag)).count();
+                    let mut all = with_tag == count;
+                    let mixed = with_tag > 0 && with_tag < count;
+                    let label = match mixed {
+                        true => { format!("{} ({} of {})###batch_{}", tag.to_str(), with_tag, count, tag.to_str()) }
+                        false => { format!("{}###batch_{}", tag.to_str(), tag.to_str()) }
+                    };
+                    if imgui_ui.checkbox(label, &mut all) {
+                        change = Some((tag.clone(), all));
+                    }
+
+                    //imgui has no tri-state checkbox, so the dash is drawn into the empty box by hand
+                    if mixed {
+                        let min = imgui_ui.item_rect_min();
+                        let side = imgui_ui.item_rect_max()[1] - min[1];
+                        let dash_min = [min[0] + side * 0.25, min[1] + side * 0.45];
+                        let dash_max = [min[0] + side * 0.75, min[1] + side * 0.55];
+                        let draw_list = imgui_ui.get_window_draw_list();
+                        draw_list.add_rect(dash_min, dash_max, imgui_ui.style_color(StyleColor::CheckMark)).filled(true).build();
+                    }
+                }
+
+                token.end();
+            }
+
+            if let (Some((tag, apply)), Some(lib)) = (change, &library) {
+                let images: Vec<(&str, &str)> = open_images.iter().filter(|im| selected_hashes.contains(&im.hash)).map(|im| (im.name.as_str(), im.hash.as_str())).collect();
+                let result = match apply {
+                    true => { lib.add_tag_to_images(&images, tag.to_str()) }
+                    false => {
+                        let hashes: Vec<&str> = images.iter().map(|(_, hash)| *hash).collect();
+                        lib.remove_tag_from_images(&hashes, tag.to_str())
+                    }
+                };
+
+                match result {
+                    Ok(_) => {
+                        for im in open_images.iter_mut().filter(|im| selected_hashes.contains(&im.hash)) {
+                            refresh_image_tags(lib, im);
+                        }
+                        if let Some(i) = selected_index {
+                            recompute_selected_tags(&mut selected_image_tags, &tags, &open_images[i].tags);
+                        }
+                    }
+                    Err(e) => {
+                        tfd::message_box_ok("Error tagging images", &format!("Couldn't update {} on {} images: {}", tag.to_str(), count, e), MessageBoxIcon::Error);
+                    }
+                }
+            }
+        }
+
+        //Full size viewer window
+        //Images can have been closed or deleted from the control panel earlier this frame
+        follow_viewer(&mut viewer, &open_images);
+        let viewed = match &mut viewer {
+            Some(v) => { open_images.get_mut(v.index).map(|im| (v, im)) }
+            None => { None }
+        };
+        if let Some((v, im)) = viewed {
+            let mut opened = true;
+            let mut step = 0;             //-1 or 1 to move through open_images
+
+            //The viewer always shows the full resolution image, with the thumbnail standing in until it arrives
+            if !im.original_requested {
+                im.original_requested = true;
+                loader_pool.queue_original(im.orignal_path.clone(), im.hash.clone());
+            }
+
+            if let Some(token) = imgui::Window::new(&format!("{}###viewer", im.name))
+                                 .opened(&mut opened)
+                                 .size([800.0, 600.0], Condition::FirstUseEver)
+                                 .scroll_bar(false)
+                                 .scrollable(false)
+                                 .begin(&imgui_ui) {
+
+                let focused = imgui_ui.is_window_focused();
+                if imgui_ui.button_with_size("Previous", [0.0, 32.0]) || (focused && imgui_ui.is_key_pressed(imgui::Key::LeftArrow)) {
+                    step = -1;
+                }
+                imgui_ui.same_line();
+                if imgui_ui.button_with_size("Next", [0.0, 32.0]) || (focused && imgui_ui.is_key_pressed(imgui::Key::RightArrow)) {
+                    step = 1;
+                }
+                imgui_ui.same_line();
+                if imgui_ui.button_with_size("Fit", [0.0, 32.0]) {
+                    v.fit = true;
+                }
+                imgui_ui.same_line();
+                let actual_size = imgui_ui.button_with_size("1:1", [0.0, 32.0]);
+                imgui_ui.same_line();
+                imgui_ui.text(format!("{:.0}%", v.zoom * 100.0));
+
+                //The rest of the window is the canvas the image is drawn on
+                let canvas_pos = imgui_ui.cursor_screen_pos();
+                let canvas_size = imgui_ui.content_region_avail();
+                if canvas_size[0] > 0.0 && canvas_size[1] > 0.0 {
+                    let image_size = [im.width as f32, im.height as f32];
+                    if v.fit {
+                        v.fit_to(image_size, canvas_size);
+                    }
+                    if actual_size {
+                        v.zoom_around(1.0, [canvas_size[0] / 2.0, canvas_size[1] / 2.0]);
+                    }
+
+                    //An invisible button over the canvas is what receives the wheel and drag input
+                    imgui_ui.invisible_button("###viewer_canvas", canvas_size);
+                    if imgui_ui.is_item_hovered() {
+                        let io = imgui_ui.io();
+                        if io.mouse_wheel != 0.0 {
+                            let anchor = [io.mouse_pos[0] - canvas_pos[0], io.mouse_pos[1] - canvas_pos[1]];
+                            v.zoom_around(v.zoom * f32::powf(1.2, io.mouse_wheel), anchor);
+                        }
+                    }
+                    if imgui_ui.is_item_active() && imgui_ui.is_mouse_dragging(imgui::MouseButton::Left) {
+                        v.pan(imgui_ui.io().mouse_delta);
+                    }
+
+                    if let Some(tex) = im.current_frame(true) {
+                        let p_min = [canvas_pos[0] + v.offset[0], canvas_pos[1] + v.offset[1]];
+                        let p_max = [p_min[0] + image_size[0] * v.zoom, p_min[1] + image_size[1] * v.zoom];
+                        let canvas_max = [canvas_pos[0] + canvas_size[0], canvas_pos[1] + canvas_size[1]];
+                        let draw_list = imgui_ui.get_window_draw_list();
+                        draw_list.with_clip_rect_intersect(canvas_pos, canvas_max, || {
+                            draw_list.add_image(TextureId::new(tex as usize), p_min, p_max).build();
+                        });
+                    }
+                }
+
+                token.end();
+            }
+
+            if step != 0 && !open_images.is_empty() {
+                let index = (v.index as i64 + step).rem_euclid(open_images.len() as i64) as usize;
+                v.show(index, open_images[index].hash.clone());
+            }
+            if !opened {
+                viewer = None;
+            }
+        }
+
+        //Slideshow window, which covers everything else while it runs
+        let mut end_slideshow = false;
+        if let Some(show) = &mut slideshow {
+            let now = frame_timer.elapsed_time;
+            if let Some(path) = show.needs_request(loader_pool.generation()) {
+                loader_pool.queue_slide(path);
+            }
+            if show.should_advance(now, slide_interval) {
+                show.advance(now);
+            }
+
+            if imgui_ui.is_key_pressed(imgui::Key::Space) {
+                show.paused = !show.paused;
+            }
+            if imgui_ui.is_key_pressed(imgui::Key::RightArrow) {
+                show.skip(1);
+            }
+            if imgui_ui.is_key_pressed(imgui::Key::LeftArrow) {
+                show.skip(-1);
+            }
+            if imgui_ui.is_key_pressed(imgui::Key::Escape) {
+                end_slideshow = true;
+            }
+
+            let screen = [window_size.x as f32, window_size.y as f32];
+            if let Some(token) = imgui::Window::new("###slideshow")
+                                 .position([0.0, 0.0], Condition::Always)
+                                 .size(screen, Condition::Always)
+                                 .title_bar(false)
+                                 .resizable(false)
+                                 .movable(false)
+                                 .scroll_bar(false)
+                                 .scrollable(false)
+                                 .focused(true)
+                                 .begin(&imgui_ui) {
+
+                let draw_list = imgui_ui.get_window_draw_list();
+                draw_list.add_rect([0.0, 0.0], screen, [0.0, 0.0, 0.0, 1.0]).filled(true).build();
+
+                //The incoming slide fades in over the outgoing one
+                let fade = match slide_fade > 0.0 {
+                    true => { f32::min((now - show.shown_at) / slide_fade, 1.0) }
+                    false => { 1.0 }
+                };
+                if fade >= 1.0 {
+                    show.previous = None;
+                }
+
+                for (texture, alpha) in [(&show.previous, 1.0), (&show.current, fade)].iter() {
+                    if let Some(tex) = texture {
+                        let scale = f32::min(screen[0] / tex.width as f32, screen[1] / tex.height as f32);
+                        let size = [tex.width as f32 * scale, tex.height as f32 * scale];
+                        let p_min = [(screen[0] - size[0]) / 2.0, (screen[1] - size[1]) / 2.0];
+                        draw_list.add_image(TextureId::new(tex.frame_at(now - show.shown_at) as usize), p_min, [p_min[0] + size[0], p_min[1] + size[1]])
+                                 .col([1.0, 1.0, 1.0, *alpha])
+                                 .build();
+                    }
+                }
+
+                if show.current.is_none() {
+                    imgui_ui.text(match show.failures >= show.paths.len() {
+                        true => { "None of these images could be loaded." }
+                        false => { "Loading..." }
+                    });
+                }
+                if show.paused {
+                    imgui_ui.text(format!("Paused ({} of {})", show.position + 1, show.paths.len()));
+                }
+
+                token.end();
+            }
+        }
+        if end_slideshow {
+            if let Some((x, y, w, h)) = windowed_rect.take() {
+                window.set_monitor(WindowMode::Windowed, x, y, w as u32, h as u32, None);
+            }
+            slideshow = None;
+        }
+
+        //Window for dealing with an image that failed to load
+        //The index is checked as the gallery may have been cleared since the tile was clicked
+        if let Some(failure_idx) = selected_failure.filter(|&i| i < failed_images.len()) {
+            enum FailureAction { Retry, Relocate, Remove, Dismiss }
+
+            let failed = &failed_images[failure_idx];
+            let mut action = None;
+            let mut opened = true;
+            if let Some(token) = imgui::Window::new("Load failure")
+                                 .opened(&mut opened)
+                                 .size([400.0, 0.0], Condition::FirstUseEver)
+                                 .begin(&imgui_ui) {
+
+                imgui_ui.text(&failed.path);
+                imgui_ui.text_colored([1.0, 0.3, 0.3, 1.0], &failed.reason);
+
+                if imgui_ui.button_with_size("Retry", [0.0, 32.0]) {
+                    action = Some(FailureAction::Retry);
+                }
+                imgui_ui.same_line();
+                if imgui_ui.button_with_size("Relocate file", [0.0, 32.0]) {
+                    action = Some(FailureAction::Relocate);
+                }
+                if failed.name.is_some() {
+                    imgui_ui.same_line();
+                    if imgui_ui.button_with_size("Remove from library", [0.0, 32.0]) {
+                        action = Some(FailureAction::Remove);
+                    }
+                }
+                imgui_ui.same_line();
+                if imgui_ui.button_with_size("Dismiss", [0.0, 32.0]) {
+                    action = Some(FailureAction::Dismiss);
+                }
+
+                token.end();
+            }
+            if !opened {
+                selected_failure = None;
+            }
+
+            match action {
+                Some(FailureAction::Retry) => {
+                    //The hash isn't passed along so that the file itself gets read instead of its cached thumbnail
+                    let failed = failed_images.remove(failure_idx);
+                    loader_pool.queue_image(failed.path, None, None);
+                    selected_failure = None;
+                }
+                Some(FailureAction::Relocate) => {
+                    if let Some(new_path) = tfd::open_file_dialog("Relocate image", &image_directory, None) {
+                        let failed = failed_images.remove(failure_idx);
+                        selected_failure = None;
+                        match (&library, &failed.name) {
+                            //Library rows keep their name and tags and just get the new file copied in under it
+                            (Some(lib), Some(name)) => {
+                                match relocate_image(lib, &image_directory, name, &new_path) {
+                                    Ok(dest) => { loader_pool.queue_image(dest, None, None); }
+                                    Err(e) => {
+                                        tfd::message_box_ok("Error relocating image", &format!("Couldn't relocate {}: {}", name, e), MessageBoxIcon::Error);
+                                        failed_images.push(failed);
+                                    }
+                                }
+                            }
+                            _ => { loader_pool.queue_image(new_path, None, None); }
+                        }
+                    }
+                }
+                Some(FailureAction::Remove) => {
+                    if let (Some(lib), Some(name)) = (&library, failed_images[failure_idx].name.clone()) {
+                        match lib.delete_image_named(&name) {
+                            Ok(_) => {
+                                let failed = failed_images.remove(failure_idx);
+                                if let Some(hash) = &failed.hash {
+                                    forget_thumbnail(lib, &loader_pool.thumbnail_dir, hash);
+                                }
+                                selected_failure = None;
+                            }
+                            Err(e) => {
+                                tfd::message_box_ok("Error removing image", &format!("Couldn't remove {} from the library: {}", name, e), MessageBoxIcon::Error);
+                            }
+                        }
+                    }
+                }
+                Some(FailureAction::Dismiss) => {
+                    failed_images.remove(failure_idx);
+                    selected_failure = None;
+                }
+                None => {}
+            }
+        }
+
+        //Window for tagging every image in the current view, including the pages that haven't been loaded yet
+        if show_result_tagging {
+            enum ResultAction { Tag(String), Untag(String), Replace(String, String) }
+            let mut action = None;
+
+            if let Some(token) = imgui::Window::new("Tag search results")
+                                 .opened(&mut show_result_tagging)
+                                 .size([400.0, 0.0], Condition::FirstUseEver)
+                                 .begin(&imgui_ui) {
+
+                match &current_view {
+                    Some(view) => {
+                        imgui_ui.text(format!("The current view has {} images.", view.ids.len()));
+
+                        imgui_ui.separator();
+                        imgui::InputText::new(&imgui_ui, "Tag", &mut result_tag_buffer).build();
+                        if !result_tag_buffer.is_empty() {
+                            if imgui_ui.button_with_size("Tag all", [0.0, 32.0]) {
+                                action = Some(ResultAction::Tag(result_tag_buffer.clone()));
+                            }
+                            imgui_ui.same_line();
+                            if imgui_ui.button_with_size("Untag all", [0.0, 32.0]) {
+                                action = Some(ResultAction::Untag(result_tag_buffer.clone()));
+                            }
+                        }
+
+                        imgui_ui.separator();
+                        if !tags.is_empty() {
+                            replace_from = usize::min(replace_from, tags.len() - 1);
+                            imgui_ui.text("Replace");
+                            imgui_ui.combo_simple_string("###Replace from", &mut replace_from, imstr_ref_array(&tags).as_slice());
+                            imgui::InputText::new(&imgui_ui, "With", &mut replace_to_buffer).build();
+                            if !replace_to_buffer.is_empty() && imgui_ui.button_with_size("Replace", [0.0, 32.0]) {
+                                action = Some(ResultAction::Replace(String::from(tags[replace_from].to_str()), replace_to_buffer.clone()));
+                            }
+                        }
+                    }
+                    None => { imgui_ui.text("Search or pick an active tag first."); }
+                }
+
+                token.end();
+            }
+
+            if let (Some(action), Some(lib), Some(view)) = (action, &library, &current_view) {
+                //Work out how many images will actually change so the user knows what they're agreeing to
+                let count_with = |tag: &str| lib.count_images(&query::and_tag(&view.expr, tag));
+                let preview = match &action {
+                    ResultAction::Tag(tag) => {
+                        lib.count_images(&view.expr).and_then(|total| count_with(tag).map(|n| format!("add \"{}\" to {} images", tag, total - n)))
+                    }
+                    ResultAction::Untag(tag) => { count_with(tag).map(|n| format!("remove \"{}\" from {} images", tag, n)) }
+                    ResultAction::Replace(from, to) => { count_with(from).map(|n| format!("replace \"{}\" with \"{}\" on {} images", from, to, n)) }
+                };
+
+                match preview {
+                    Ok(preview) => {
+                        let answer = tfd::message_box_yes_no("Tag search results", &format!("This will {}.\nProceed?", preview), MessageBoxIcon::Question, YesNo::No);
+                        if matches!(answer, YesNo::Yes) {
+                            let result = match &action {
+                                ResultAction::Tag(tag) => { lib.add_tag_to_query(&view.expr, tag) }
+                                ResultAction::Untag(tag) => { lib.remove_tag_from_query(&view.expr, tag) }
+                                ResultAction::Replace(from, to) => { lib.replace_tag_in_query(&view.expr, from, to) }
+                            };
+                            match result {
+                                Ok(_) => {
+                                    reload_all_tags(&library, &mut tags, &mut selected_image_tags, &mut open_images, selected_index);
+
+                                    //The results may no longer match the query, so run it again
+                                    refresh_view = true;
+                                }
+                                Err(e) => {
+                                    tfd::message_box_ok("Error tagging search results", &format!("Nothing was changed: {}", e), MessageBoxIcon::Error);
+                                }
+                            }
+                        }
+                    }
+                    Err(e) => { println!("Error counting images: {}", e); }
+                }
+            }
+        }
+
+        //Tag management window
+        if show_tag_manager {
+            enum TagAction {
+                Rename(String, String),
+                Merge(String, String),
+                Delete(String, usize),
+                AddAlias(String, String),
+                RemoveAlias(String),
+                AddImplication(String, String, bool),
+                RemoveImplication(String, String),
+                AddParent(String, String),
+                RemoveParent(String, String)
+            }
+            let mut action = None;
+
+            if refresh_tag_usage {
+                refresh_tag_usage = false;
+                match &library {
+                    Some(lib) => {
+                        tag_usage = lib.tag_usage().unwrap_or_else(|e| {
+                            println!("Error fetching tag usage: {}", e);
+                            vec![]
+                        });
+                        tag_aliases = lib.aliases().unwrap_or_else(|e| {
+                            println!("Error fetching tag aliases: {}", e);
+                            vec![]
+                        });
+                        tag_implications = lib.implications().unwrap_or_else(|e| {
+                            println!("Error fetching tag implications: {}", e);
+                            vec![]
+                        });
+                        tag_parents = lib.parents().unwrap_or_else(|e| {
+                            println!("Error fetching tag parents: {}", e);
+                            vec![]
+                        });
+                    }
+                    None => {
+                        tag_usage.clear();
+                        tag_aliases.clear();
+                        tag_implications.clear();
+                        tag_parents.clear();
+                    }
+                }
+            }
+
+            if let Some(token) = imgui::Window::new("Manage tags")
+                                 .opened(&mut show_tag_manager)
+                                 .size([400.0, 500.0], Condition::FirstUseEver)
+                                 .begin(&imgui_ui) {
+
+                //Rename and merge controls for the tag that's being edited
+                if let Some(tag) = &editing_tag {
+                    imgui_ui.text(format!("Editing \"{}\"", tag));
+                    imgui::InputText::new(&imgui_ui, "New name", &mut rename_buffer).build();
+                    if !rename_buffer.is_empty() && imgui_ui.button_with_size("Rename", [0.0, 32.0]) {
+                        action = Some(TagAction::Rename(tag.clone(), rename_buffer.clone()));
+                    }
+
+                    if !tags.is_empty() {
+                        merge_target = usize::min(merge_target, tags.len() - 1);
+                        imgui_ui.combo_simple_string("Merge into", &mut merge_target, imstr_ref_array(&tags).as_slice());
+                        if imgui_ui.button_with_size("Merge", [0.0, 32.0]) {
+                            action = Some(TagAction::Merge(tag.clone(), String::from(tags[merge_target].to_str())));
+                        }
+                    }
+
+                    //What the tag implies, which gets applied along with it
+                    imgui_ui.text("Implies:");
+                    for (i, implication) in tag_implications.iter().filter(|im| im.tag == *tag).enumerate() {
+                        if imgui_ui.small_button(format!("Remove###remove_implication{}", i)) {
+                            action = Some(TagAction::RemoveImplication(tag.clone(), implication.implied.clone()));
+                        }
+                        imgui_ui.same_line();
+                        match implication.materialized {
+                            true => { imgui_ui.text(&implication.implied); }
+                            false => { imgui_ui.text(format!("{} (search only)", implication.implied)); }
+                        }
+                    }
+                    if !tags.is_empty() {
+                        implied_target = usize::min(implied_target, tags.len() - 1);
+                        imgui_ui.combo_simple_string("###Implied tag", &mut implied_target, imstr_ref_array(&tags).as_slice());
+                        imgui_ui.checkbox("Write onto images", &mut materialize_implication);
+                        if imgui_ui.button_with_size("Add implication", [0.0, 32.0]) {
+                            action = Some(TagAction::AddImplication(tag.clone(), String::from(tags[implied_target].to_str()), materialize_implication));
+                        }
+                    }
+
+                    //Where the tag sits in the hierarchy that under: searches go down
+                    imgui_ui.text("Parents:");
+                    for (i, (_, parent)) in tag_parents.iter().filter(|(t, _)| t == tag).enumerate() {
+                        if imgui_ui.small_button(format!("Remove###remove_parent{}", i)) {
+                            action = Some(TagAction::RemoveParent(tag.clone(), parent.clone()));
+                        }
+                        imgui_ui.same_line();
+                        imgui_ui.text(parent);
+                    }
+                    if !tags.is_empty() {
+                        parent_target = usize::min(parent_target, tags.len() - 1);
+                        imgui_ui.combo_simple_string("###Parent tag", &mut parent_target, imstr_ref_array(&tags).as_slice());
+                        if imgui_ui.button_with_size("Add parent", [0.0, 32.0]) {
+                            action = Some(TagAction::AddParent(tag.clone(), String::from(tags[parent_target].to_str())));
+                        }
+                    }
+
+                    if imgui_ui.button_with_size("Done", [0.0, 32.0]) {
+                        editing_tag = None;
+                    }
+                    imgui_ui.separator();
+                }
+
+                for (i, (tag, count)) in tag_usage.iter().enumerate() {
+                    if imgui_ui.small_button(format!("Edit###edit_tag{}", i)) {
+                        editing_tag = Some(tag.clone());
+                        rename_buffer = tag.clone();
+                    }
+                    imgui_ui.same_line();
+                    if imgui_ui.small_button(format!("Delete###delete_tag{}", i)) {
+                        action = Some(TagAction::Delete(tag.clone(), *count));
+                    }
+                    imgui_ui.same_line();
+                    imgui_ui.text(format!("{} ({})", tag, count));
+                }
+
+                //Alternate spellings that get swapped for the tag they stand for when applying tags or searching
+                imgui_ui.separator();
+                imgui_ui.text("Aliases:");
+                if !tags.is_empty() {
+                    imgui::InputText::new(&imgui_ui, "Alias", &mut alias_buffer).build();
+                    alias_target = usize::min(alias_target, tags.len() - 1);
+                    imgui_ui.combo_simple_string("Stands for", &mut alias_target, imstr_ref_array(&tags).as_slice());
+                    if !alias_buffer.is_empty() && imgui_ui.button_with_size("Add alias", [0.0, 32.0]) {
+                        action = Some(TagAction::AddAlias(alias_buffer.clone(), String::from(tags[alias_target].to_str())));
+                    }
+                }
+
+                for (i, (alias, tag)) in tag_aliases.iter().enumerate() {
+                    if imgui_ui.small_button(format!("Remove###remove_alias{}", i)) {
+                        action = Some(TagAction::RemoveAlias(alias.clone()));
+                    }
+                    imgui_ui.same_line();
+                    imgui_ui.text(format!("{} -> {}", alias, tag));
+                }
+
+                token.end();
+            }
+
+            if let (Some(action), Some(lib)) = (action, &library) {
+                let tag_changed = matches!(action, TagAction::Rename(..) | TagAction::Merge(..) | TagAction::Delete(..));
+                let result = match action {
+                    TagAction::Rename(tag, new_name) => { name_result(lib.rename_tag(&tag, &new_name)) }
+                    TagAction::Merge(tag, into) => {
+                        let message = format!("Every image tagged \"{}\" will be tagged \"{}\" instead, and \"{}\" will become an alias of \"{}\".\nProceed?", tag, into, tag, into);
+                        match tfd::message_box_yes_no("Merge tags", &message, MessageBoxIcon::Question, YesNo::No) {
+                            YesNo::Yes => { relation_result(lib.merge_tag(&tag, &into)) }
+                            YesNo::No => { Ok(false) }
+                        }
+                    }
+                    TagAction::Delete(tag, count) => {
+                        let message = format!("You are about to delete \"{}\", which is on {} images.\nProceed?", tag, count);
+                        match count == 0 || matches!(tfd::message_box_yes_no("Delete tag", &message, MessageBoxIcon::Warning, YesNo::No), YesNo::Yes) {
+                            true => { lib.delete_tag(&tag).map(|_| true) }
+                            false => { Ok(false) }
+                        }
+                    }
+                    TagAction::AddAlias(alias, tag) => {
+                        let result = name_result(lib.add_alias(&alias, &tag));
+                        if let Ok(true) = result {
+                            alias_buffer.clear();
+                        }
+                        result
+                    }
+                    TagAction::RemoveAlias(alias) => { lib.remove_alias(&alias).map(|_| true) }
+                    TagAction::AddImplication(tag, implied, materialize) => { relation_result(lib.add_implication(&tag, &implied, materialize)) }
+                    TagAction::RemoveImplication(tag, implied) => { lib.remove_implication(&tag, &implied).map(|_| true) }
+                    TagAction::AddParent(tag, parent) => { relation_result(lib.add_parent(&tag, &parent)) }
+                    TagAction::RemoveParent(tag, parent) => { lib.remove_parent(&tag, &parent).map(|_| true) }
+                };
+
+                match result {
+                    Ok(true) => {
+                        if tag_changed {
+                            editing_tag = None;
+                        }
+                        refresh_tag_usage = true;
+                        reload_all_tags(&library, &mut tags, &mut selected_image_tags, &mut open_images, selected_index);
+                        selected_tag = usize::min(selected_tag, tags.len().saturating_sub(1));
+                    }
+                    Ok(false) => {}
+                    Err(e) => {
+                        tfd::message_box_ok("Error editing tag", &format!("Nothing was changed: {}", e), MessageBoxIcon::Error);
+                    }
+                }
+            }
+        }
+
+        //Duplicate finder window
+        if show_duplicate_finder {
+            let mut keep_action = None;
+            if let Some(token) = imgui::Window::new("Duplicate finder")
+                                 .opened(&mut show_duplicate_finder)
+                                 .size([500.0, 400.0], Condition::FirstUseEver)
+                                 .begin(&imgui_ui) {
+
+                imgui_ui.text("Maximum difference (bits)");
+                imgui::Slider::new("###Maximum difference", 0, 20).build(&imgui_ui, &mut duplicate_distance);
+
+                if imgui_ui.button_with_size("Scan library", [0.0, 32.0]) {
+                    match &library {
+                        Some(lib) => {
+                            match lib.image_fingerprints() {
+                                Ok(fingerprints) => { duplicate_groups = duplicates::group_similar(fingerprints, duplicate_distance); }
+                                Err(e) => { println!("Error fetching fingerprints: {}", e); }
+                            }
+                        }
+                        None => {
+                            tfd::message_box_ok("No loaded database", "You need to open a database before you can do this", MessageBoxIcon::Error);
+                        }
+                    }
+                }
+                imgui_ui.text(format!("{} groups of similar images.", duplicate_groups.len()));
+                imgui_ui.text("Images are fingerprinted the first time they're loaded.");
+                imgui_ui.checkbox("Delete the others when keeping one", &mut delete_duplicates);
+
+                for (i, group) in duplicate_groups.iter().enumerate() {
+                    imgui_ui.separator();
+                    if imgui_ui.button(format!("Load group###load_group{}", i)) {
+                        clear_open_images(&mut open_images, &mut selected_index, &mut failed_images, &mut loader_pool);
+                        current_view = None;
+                        for image in group {
+                            let metadata = library.as_ref().and_then(|lib| lib.image_metadata(&image.hash).unwrap_or(None));
+                            loader_pool.queue_image(format!("{}/{}", image_directory, image.name), Some(image.hash.clone()), metadata);
+                        }
+                    }
+
+                    for (j, image) in group.iter().enumerate() {
+                        if imgui_ui.small_button(format!("Keep###keep{}_{}", i, j)) {
+                            keep_action = Some((i, j));
+                        }
+                        imgui_ui.same_line();
+                        imgui_ui.text(&image.name);
+                    }
+                }
+
+                token.end();
+            }
+
+            //Merge the group's tags onto the chosen image, then get rid of the rest
+            if let Some((group_idx, keep_idx)) = keep_action {
+                let proceed = !delete_duplicates || {
+                    let message = format!("You are about to permanently delete {} images.\nProceed?", duplicate_groups[group_idx].len() - 1);
+                    let answer = tfd::message_box_yes_no("Delete duplicates", &message, MessageBoxIcon::Warning, YesNo::No);
+                    matches!(answer, YesNo::Yes)
+                };
+
+                if let (true, Some(lib)) = (proceed, &library) {
+                    let group = &duplicate_groups[group_idx];
+                    let keep = &group[keep_idx];
+                    let others: Vec<&ImageFingerprint> = group.iter().filter(|f| f.hash != keep.hash).collect();
+                    let other_hashes: Vec<&str> = others.iter().map(|f| f.hash.as_str()).collect();
+
+                    match lib.merge_duplicates(&keep.hash, &other_hashes, delete_duplicates) {
+                        Ok(_) => {
+                            //Files only go once the database no longer refers to them
+                            if delete_duplicates {
+                                for other in others.iter() {
+                                    let path = format!("{}/{}", image_directory, other.name);
+                                    if let Err(e) = fs::remove_file(&path) {
+                                        println!("Error deleting {}: {}", path, e);
+                                    }
+                                    forget_thumbnail(lib, &loader_pool.thumbnail_dir, &other.hash);
+                                }
+                            }
+
+                            //Refresh the survivor's tags if it's open
+                            for im in open_images.iter_mut().filter(|im| im.hash == keep.hash) {
+                                refresh_image_tags(lib, im);
+                            }
+
+                            //Close the others
+                            selected_index = None;
+                            open_images.retain(|im| !other_hashes.contains(&im.hash.as_str()));
+                            duplicate_groups.remove(group_idx);
+                        }
+                        Err(e) => {
+                            tfd::message_box_ok("Error merging duplicates", &format!("Nothing was changed: {}", e), MessageBoxIcon::Error);
+                        }
+                    }
+                }
+            }
+        }
+
         //Rendering Dear IMGUI
         unsafe {
             gl::Clear(gl::COLOR_BUFFER_BIT);            
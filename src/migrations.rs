@@ -0,0 +1,275 @@
+use sqlite::State;
+
+use crate::library::OpenError;
+
+//Written to PRAGMA application_id so that we can recognize our own database files
+pub const APPLICATION_ID: i64 = 0x75777564;     //"uwud"
+
+//Tables that a library created before schema versioning existed will contain
+const LEGACY_TABLES: [&str; 3] = ["images", "tags", "image_tags"];
+
+struct Migration {
+    sql: &'static str,
+    fixed_rows: Option<&'static str>        //Query run before the step that counts how many bad rows it will clean up
+}
+
+//Ordered schema upgrade steps. MIGRATIONS[n] takes a database from user_version n to n + 1
+//Steps must only ever be appended to this list, never edited, as older libraries in the wild depend on them
+//They are run with foreign key enforcement off so that tables can be rebuilt without cascading deletes
+const MIGRATIONS: &[Migration] = &[
+    //Version 1: the original schema. IF NOT EXISTS lets this also adopt libraries created before versioning
+    Migration {
+        sql: "
+            CREATE TABLE IF NOT EXISTS images (id INTEGER, path STRING NOT NULL UNIQUE, PRIMARY KEY (id));
+            CREATE TABLE IF NOT EXISTS tags (id INTEGER, name STRING NOT NULL UNIQUE, PRIMARY KEY (id));
+            CREATE TABLE IF NOT EXISTS image_tags (image_id INTEGER, tag_id INTEGER);
+        ",
+        fixed_rows: None
+    },
+
+    //Version 2: image_tags gets a composite primary key and cascading foreign keys
+    //Duplicate rows and rows pointing at missing (or NULL) images and tags are dropped in the copy
+    Migration {
+        sql: "
+            CREATE TABLE image_tags_new (
+                image_id INTEGER NOT NULL REFERENCES images (id) ON DELETE CASCADE,
+                tag_id INTEGER NOT NULL REFERENCES tags (id) ON DELETE CASCADE,
+                PRIMARY KEY (image_id, tag_id)
+            );
+            INSERT OR IGNORE INTO image_tags_new (image_id, tag_id)
+                SELECT image_id, tag_id FROM image_tags
+                WHERE image_id IN (SELECT id FROM images) AND tag_id IN (SELECT id FROM tags);
+            DROP TABLE image_tags;
+            ALTER TABLE image_tags_new RENAME TO image_tags;
+            CREATE INDEX image_tags_tag_id ON image_tags (tag_id);
+        ",
+        fixed_rows: Some("
+            SELECT (SELECT COUNT(*) FROM image_tags) - (
+                SELECT COUNT(*) FROM (
+                    SELECT DISTINCT image_id, tag_id FROM image_tags
+                    WHERE image_id IN (SELECT id FROM images) AND tag_id IN (SELECT id FROM tags)
+                )
+            );
+        ")
+    },
+
+    //Version 3: images are identified by the SHA-256 of their contents
+    //Existing rows start out NULL and are hashed lazily the next time they are loaded
+    Migration {
+        sql: "
+            ALTER TABLE images ADD COLUMN hash TEXT;
+            CREATE UNIQUE INDEX images_hash ON images (hash);
+        ",
+        fixed_rows: None
+    },
+
+    //Version 4: perceptual hash for near-duplicate detection, filled in as images pass through the loader
+    Migration {
+        sql: "ALTER TABLE images ADD COLUMN phash INTEGER;",
+        fixed_rows: None
+    },
+
+    //Version 5: intrinsic metadata so that filtering and sorting don't have to decode anything
+    //Times are unix timestamps in seconds. Existing rows are filled in the next time they are loaded
+    Migration {
+        sql: "
+            ALTER TABLE images ADD COLUMN width INTEGER;
+            ALTER TABLE images ADD COLUMN height INTEGER;
+            ALTER TABLE images ADD COLUMN size INTEGER;
+            ALTER TABLE images ADD COLUMN format TEXT;
+            ALTER TABLE images ADD COLUMN modified INTEGER;
+            ALTER TABLE images ADD COLUMN imported INTEGER;
+        ",
+        fixed_rows: None
+    },
+
+    //Version 6: alternate spellings of a tag, which are swapped for the tag itself wherever a tag name is typed in
+    Migration {
+        sql: "
+            CREATE TABLE tag_aliases (
+                alias STRING NOT NULL PRIMARY KEY,
+                tag_id INTEGER NOT NULL REFERENCES tags (id) ON DELETE CASCADE
+            );
+            CREATE INDEX tag_aliases_tag_id ON tag_aliases (tag_id);
+        ",
+        fixed_rows: None
+    },
+
+    //Version 7: relations between tags
+    //An implication means images with tag_id also count as having implied_id. Materialized ones are also written to image_tags
+    //A parent groups tags under it, so that searching for the parent can include its descendants
+    Migration {
+        sql: "
+            CREATE TABLE tag_implications (
+                tag_id INTEGER NOT NULL REFERENCES tags (id) ON DELETE CASCADE,
+                implied_id INTEGER NOT NULL REFERENCES tags (id) ON DELETE CASCADE,
+                materialized INTEGER NOT NULL DEFAULT 1,
+                PRIMARY KEY (tag_id, implied_id)
+            );
+            CREATE INDEX tag_implications_implied_id ON tag_implications (implied_id);
+            CREATE TABLE tag_parents (
+                tag_id INTEGER NOT NULL REFERENCES tags (id) ON DELETE CASCADE,
+                parent_id INTEGER NOT NULL REFERENCES tags (id) ON DELETE CASCADE,
+                PRIMARY KEY (tag_id, parent_id)
+            );
+            CREATE INDEX tag_parents_parent_id ON tag_parents (parent_id);
+        ",
+        fixed_rows: None
+    }
+];
+
+//What happened while bringing a library up to date
+pub struct MigrationReport {
+    pub from_version: i64,
+    pub fixed_rows: i64             //Number of duplicate or orphaned rows that were cleaned up
+}
+
+//The schema version this build of the program reads and writes
+pub fn current_version() -> i64 {
+    MIGRATIONS.len() as i64
+}
+
+fn query_i64(con: &sqlite::Connection, sql: &str) -> sqlite::Result<i64> {
+    let mut statement = con.prepare(sql)?;
+    match statement.next()? {
+        State::Row => { statement.read::<i64>(0) }
+        State::Done => { Ok(0) }
+    }
+}
+
+fn table_names(con: &sqlite::Connection) -> sqlite::Result<Vec<String>> {
+    let mut statement = con.prepare("SELECT name FROM sqlite_master WHERE type='table' AND name NOT LIKE 'sqlite_%';")?;
+    let mut names = Vec::new();
+    while let State::Row = statement.next()? {
+        names.push(statement.read::<String>(0)?);
+    }
+    Ok(names)
+}
+
+//Brings the database up to current_version(), one transaction per step
+//Foreign key enforcement is turned on once the schema is current
+pub fn migrate(con: &sqlite::Connection) -> Result<MigrationReport, OpenError> {
+    let application_id = query_i64(con, "PRAGMA application_id;")?;
+    let version = query_i64(con, "PRAGMA user_version;")?;
+
+    if application_id != 0 && application_id != APPLICATION_ID {
+        return Err(OpenError::NotALibrary);
+    }
+
+    //An unversioned file is either brand new or a library from before versioning existed
+    if version == 0 && table_names(con)?.iter().any(|t| !LEGACY_TABLES.contains(&t.as_str())) {
+        return Err(OpenError::NotALibrary);
+    }
+
+    if version > current_version() {
+        return Err(OpenError::NewerVersion(version));
+    }
+
+    let mut report = MigrationReport {
+        from_version: version,
+        fixed_rows: 0
+    };
+
+    //This pragma is a no-op inside of a transaction, so it has to be set out here
+    con.execute("PRAGMA foreign_keys = OFF;")?;
+    for (i, step) in MIGRATIONS.iter().enumerate().skip(version as usize) {
+        con.execute("BEGIN;")?;
+        let result = match step.fixed_rows {
+            Some(query) => { query_i64(con, query) }
+            None => { Ok(0) }
+        }.and_then(|fixed| {
+            con.execute(step.sql)?;
+            con.execute(format!("PRAGMA user_version = {}; PRAGMA application_id = {};", i + 1, APPLICATION_ID))?;
+            Ok(fixed)
+        });
+
+        match result {
+            Ok(fixed) => {
+                con.execute("COMMIT;")?;
+                report.fixed_rows += fixed;
+            }
+            Err(e) => {
+                if let Err(rollback_e) = con.execute("ROLLBACK;") {
+                    println!("Error rolling back migration: {}", rollback_e);
+                }
+                return Err(OpenError::Migration(i as i64 + 1, e));
+            }
+        }
+    }
+
+    con.execute("PRAGMA foreign_keys = ON;")?;
+
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn migrated(con: &sqlite::Connection) -> MigrationReport {
+        match migrate(con) {
+            Ok(report) => { report }
+            Err(e) => { panic!("Migration failed: {}", e) }
+        }
+    }
+
+    fn refused(con: &sqlite::Connection) -> OpenError {
+        match migrate(con) {
+            Ok(_) => { panic!("Migration should have been refused") }
+            Err(e) => { e }
+        }
+    }
+
+    #[test]
+    fn new_database_is_brought_up_to_date() {
+        let con = sqlite::open(":memory:").unwrap();
+        let report = migrated(&con);
+        assert_eq!(report.from_version, 0);
+        assert_eq!(report.fixed_rows, 0);
+        assert_eq!(query_i64(&con, "PRAGMA user_version;").unwrap(), current_version());
+        assert_eq!(query_i64(&con, "PRAGMA application_id;").unwrap(), APPLICATION_ID);
+
+        //Opening it again has nothing left to do
+        let report = migrated(&con);
+        assert_eq!(report.from_version, current_version());
+        assert_eq!(report.fixed_rows, 0);
+    }
+
+    #[test]
+    fn legacy_duplicate_and_orphaned_rows_are_counted_and_dropped() {
+        let con = sqlite::open(":memory:").unwrap();
+        con.execute("
+            CREATE TABLE images (id INTEGER, path STRING NOT NULL UNIQUE, PRIMARY KEY (id));
+            CREATE TABLE tags (id INTEGER, name STRING NOT NULL UNIQUE, PRIMARY KEY (id));
+            CREATE TABLE image_tags (image_id INTEGER, tag_id INTEGER);
+            INSERT INTO images (id, path) VALUES (1, 'a.png');
+            INSERT INTO tags (id, name) VALUES (1, 'cat');
+            INSERT INTO image_tags VALUES (1, 1), (1, 1), (1, 1), (1, 2), (2, 1), (NULL, 1);
+        ").unwrap();
+
+        let report = migrated(&con);
+        assert_eq!(report.from_version, 0);
+        assert_eq!(report.fixed_rows, 5);
+        assert_eq!(query_i64(&con, "SELECT COUNT(*) FROM image_tags;").unwrap(), 1);
+        assert_eq!(query_i64(&con, "SELECT COUNT(*) FROM images WHERE path='a.png' AND hash IS NULL;").unwrap(), 1);
+        assert_eq!(query_i64(&con, "PRAGMA foreign_keys;").unwrap(), 1);
+    }
+
+    #[test]
+    fn foreign_databases_are_refused() {
+        let con = sqlite::open(":memory:").unwrap();
+        con.execute("CREATE TABLE notes (body TEXT);").unwrap();
+        assert!(matches!(refused(&con), OpenError::NotALibrary));
+
+        let con = sqlite::open(":memory:").unwrap();
+        con.execute("PRAGMA application_id = 1234;").unwrap();
+        assert!(matches!(refused(&con), OpenError::NotALibrary));
+    }
+
+    #[test]
+    fn newer_schema_is_refused() {
+        let con = sqlite::open(":memory:").unwrap();
+        con.execute(format!("PRAGMA application_id = {}; PRAGMA user_version = 99;", APPLICATION_ID)).unwrap();
+        assert!(matches!(refused(&con), OpenError::NewerVersion(99)));
+    }
+}
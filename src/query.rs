@@ -0,0 +1,576 @@
+use std::fmt;
+
+//Search expressions typed into the gallery's search box, e.g.
+//    fox_girl AND (smug OR gyaru) AND NOT sketch
+//Terms next to each other without an operator are ANDed together, and tags containing spaces can be "quoted"
+//Tag aliases are searched as the tag they stand for, and a tag also matches the images with tags that imply it
+//
+//Terms of the form field:value filter on stored image metadata instead of tags:
+//    width:>=1920  height:<1080  size:>2mb  format:png  tagcount:<3
+//    ratio:16:9  ratio:>1.5  ratio:portrait  ratio:landscape  ratio:square
+//    imported:<7d  modified:>1y  imported:>=2021-05-01
+//Durations (h, d, w, m, y) compare against how long ago something happened, so imported:<7d means within the last week
+//A date covers that whole day in UTC, so imported:2021-05-01 matches anything imported on it and modified:>2021-05-01 starts the day after
+//Images whose metadata hasn't been recorded yet never match a metadata filter
+//
+//under:animal_ears matches animal_ears along with every tag below it in the tag hierarchy, such as fox_ears and cat_ears
+
+#[derive(Clone, Copy)]
+pub enum Field {
+    Width,
+    Height,
+    Size,
+    Ratio,
+    TagCount,
+    Imported,
+    Modified,
+    ImportedAge,
+    ModifiedAge
+}
+
+#[derive(Clone, Copy)]
+pub enum Comparison {
+    Less,
+    LessEqual,
+    Equal,
+    GreaterEqual,
+    Greater
+}
+
+#[derive(Clone)]
+pub enum Expr {
+    Tag(String),
+    ExactTag(String),                   //Only images that carry the tag itself, ignoring implications. Never produced by the parser
+    Descendants(String),
+    Compare(Field, Comparison, sqlite::Value),
+    Format(String),
+    Not(Box<Expr>),
+    And(Box<Expr>, Box<Expr>),
+    Or(Box<Expr>, Box<Expr>)
+}
+
+pub struct ParseError {
+    pub message: String,
+    pub position: usize                 //Character offset into the query where the problem is
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} (at character {})", self.message, self.position + 1)
+    }
+}
+
+enum Token {
+    Word(String),
+    Quoted(String),
+    And,
+    Or,
+    Not,
+    LeftParen,
+    RightParen
+}
+
+fn error<T>(message: &str, position: usize) -> Result<T, ParseError> {
+    Err(ParseError {
+        message: String::from(message),
+        position
+    })
+}
+
+//Splits the query into tokens along with the character position each one starts at
+fn tokenize(query: &str) -> Result<Vec<(Token, usize)>, ParseError> {
+    let chars: Vec<char> = query.chars().collect();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        if c.is_whitespace() {
+            i += 1;
+        } else if c == '(' {
+            tokens.push((Token::LeftParen, i));
+            i += 1;
+        } else if c == ')' {
+            tokens.push((Token::RightParen, i));
+            i += 1;
+        } else if c == '"' {
+            let start = i;
+            i += 1;
+            let mut word = String::new();
+            while i < chars.len() && chars[i] != '"' {
+                word.push(chars[i]);
+                i += 1;
+            }
+            if i == chars.len() {
+                return error("Unterminated quote", start);
+            }
+            i += 1;
+            tokens.push((Token::Quoted(word), start));
+        } else {
+            let start = i;
+            let mut word = String::new();
+            while i < chars.len() && !chars[i].is_whitespace() && chars[i] != '(' && chars[i] != ')' && chars[i] != '"' {
+                word.push(chars[i]);
+                i += 1;
+            }
+
+            //Operators are case-insensitive. Quote a tag to search for one literally named "and"
+            let token = match word.to_uppercase().as_str() {
+                "AND" => { Token::And }
+                "OR" => { Token::Or }
+                "NOT" => { Token::Not }
+                _ => { Token::Word(word) }
+            };
+            tokens.push((token, start));
+        }
+    }
+    Ok(tokens)
+}
+
+fn is_date(s: &str) -> bool {
+    let parts: Vec<&str> = s.split('-').collect();
+    parts.len() == 3 && parts[0].len() == 4 && parts[1].len() == 2 && parts[2].len() == 2 &&
+    parts.iter().all(|p| p.chars().all(|c| c.is_ascii_digit()))
+}
+
+//Byte count from e.g. "500", "300kb" or "2mb"
+fn parse_size(s: &str) -> Option<i64> {
+    let lower = s.to_lowercase();
+    let (number, multiplier) = if let Some(n) = lower.strip_suffix("gb") {
+        (n, 1024 * 1024 * 1024)
+    } else if let Some(n) = lower.strip_suffix("mb") {
+        (n, 1024 * 1024)
+    } else if let Some(n) = lower.strip_suffix("kb") {
+        (n, 1024)
+    } else {
+        (lower.strip_suffix('b').unwrap_or(&lower), 1)
+    };
+    number.parse::<f64>().ok().map(|n| (n * multiplier as f64) as i64)
+}
+
+//Seconds from e.g. "12h", "7d", "2w", "6m" or "1y"
+fn parse_duration(s: &str) -> Option<i64> {
+    let unit = s.chars().last()?;
+    let seconds = match unit {
+        'h' => { 60 * 60 }
+        'd' => { 60 * 60 * 24 }
+        'w' => { 60 * 60 * 24 * 7 }
+        'm' => { 60 * 60 * 24 * 30 }
+        'y' => { 60 * 60 * 24 * 365 }
+        _ => { return None; }
+    };
+    s[..s.len() - 1].parse::<i64>().ok().map(|n| n * seconds)
+}
+
+//Width over height from e.g. "16:9" or "1.5"
+fn parse_ratio(s: &str) -> Option<f64> {
+    match s.find(':') {
+        Some(i) => {
+            let w = s[..i].parse::<f64>().ok()?;
+            let h = s[i + 1..].parse::<f64>().ok()?;
+            if h == 0.0 { None } else { Some(w / h) }
+        }
+        None => { s.parse::<f64>().ok() }
+    }
+}
+
+//Parses the field:value form. Returns Ok(None) if field isn't a metadata field, in which case the word is a tag
+fn parse_predicate(word: &str, position: usize) -> Result<Option<Expr>, ParseError> {
+    let colon = match word.find(':') {
+        Some(i) => { i }
+        None => { return Ok(None); }
+    };
+    let field = word[..colon].to_lowercase();
+    let rest = &word[colon + 1..];
+    let value_position = position + word[..colon + 1].chars().count();
+
+    if field == "under" {
+        return match rest.is_empty() {
+            true => { error("Expected a tag", value_position) }
+            false => { Ok(Some(Expr::Descendants(String::from(rest)))) }
+        };
+    }
+
+    let (comparison, value) = if let Some(v) = rest.strip_prefix(">=") {
+        (Comparison::GreaterEqual, v)
+    } else if let Some(v) = rest.strip_prefix("<=") {
+        (Comparison::LessEqual, v)
+    } else if let Some(v) = rest.strip_prefix('>') {
+        (Comparison::Greater, v)
+    } else if let Some(v) = rest.strip_prefix('<') {
+        (Comparison::Less, v)
+    } else if let Some(v) = rest.strip_prefix('=') {
+        (Comparison::Equal, v)
+    } else {
+        (Comparison::Equal, rest)
+    };
+
+    let integer = |field: Field, v: Option<i64>, what: &str| {
+        match v {
+            Some(n) => { Ok(Some(Expr::Compare(field, comparison, sqlite::Value::Integer(n)))) }
+            None => { error(&format!("Expected {}", what), value_position) }
+        }
+    };
+
+    match field.as_str() {
+        "width" => { integer(Field::Width, value.parse().ok(), "a width in pixels") }
+        "height" => { integer(Field::Height, value.parse().ok(), "a height in pixels") }
+        "tagcount" => { integer(Field::TagCount, value.parse().ok(), "a number of tags") }
+        "size" => { integer(Field::Size, parse_size(value), "a file size like 500kb or 2mb") }
+        "format" => {
+            let format = match value.to_lowercase().as_str() {
+                "jpg" => { String::from("jpeg") }
+                "tif" => { String::from("tiff") }
+                f => { String::from(f) }
+            };
+            if format.is_empty() {
+                error("Expected a format like png or jpg", value_position)
+            } else {
+                Ok(Some(Expr::Format(format)))
+            }
+        }
+        "ratio" => {
+            match value.to_lowercase().as_str() {
+                "portrait" => { Ok(Some(Expr::Compare(Field::Ratio, Comparison::Less, sqlite::Value::Float(1.0)))) }
+                "landscape" => { Ok(Some(Expr::Compare(Field::Ratio, Comparison::Greater, sqlite::Value::Float(1.0)))) }
+                "square" => { Ok(Some(Expr::Compare(Field::Ratio, Comparison::Equal, sqlite::Value::Float(1.0)))) }
+                _ => {
+                    match parse_ratio(value) {
+                        Some(r) => { Ok(Some(Expr::Compare(Field::Ratio, comparison, sqlite::Value::Float(r)))) }
+                        None => { error("Expected a ratio like 16:9, 1.5 or portrait", value_position) }
+                    }
+                }
+            }
+        }
+        "imported" | "modified" => {
+            let (absolute, age) = if field == "imported" {
+                (Field::Imported, Field::ImportedAge)
+            } else {
+                (Field::Modified, Field::ModifiedAge)
+            };
+
+            if is_date(value) {
+                Ok(Some(Expr::Compare(absolute, comparison, sqlite::Value::String(String::from(value)))))
+            } else {
+                integer(age, parse_duration(value), "a date like 2021-05-01 or a duration like 7d")
+            }
+        }
+        _ => { Ok(None) }
+    }
+}
+
+//Recursive descent parser. Precedence from loosest to tightest is OR, AND, NOT
+struct Parser {
+    tokens: Vec<(Token, usize)>,
+    current: usize,
+    end: usize                          //Character position of the end of the query for error reporting
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.current).map(|(t, _)| t)
+    }
+
+    fn position(&self) -> usize {
+        match self.tokens.get(self.current) {
+            Some((_, p)) => { *p }
+            None => { self.end }
+        }
+    }
+
+    fn or_expr(&mut self) -> Result<Expr, ParseError> {
+        let mut expr = self.and_expr()?;
+        while let Some(Token::Or) = self.peek() {
+            self.current += 1;
+            let rhs = self.and_expr()?;
+            expr = Expr::Or(Box::new(expr), Box::new(rhs));
+        }
+        Ok(expr)
+    }
+
+    fn and_expr(&mut self) -> Result<Expr, ParseError> {
+        let mut expr = self.not_expr()?;
+        loop {
+            match self.peek() {
+                Some(Token::And) => { self.current += 1; }
+                Some(Token::Word(_)) | Some(Token::Quoted(_)) | Some(Token::Not) | Some(Token::LeftParen) => {}     //Implicit AND
+                _ => { break; }
+            }
+            let rhs = self.not_expr()?;
+            expr = Expr::And(Box::new(expr), Box::new(rhs));
+        }
+        Ok(expr)
+    }
+
+    fn not_expr(&mut self) -> Result<Expr, ParseError> {
+        if let Some(Token::Not) = self.peek() {
+            self.current += 1;
+            let expr = self.not_expr()?;
+            return Ok(Expr::Not(Box::new(expr)));
+        }
+        self.term()
+    }
+
+    fn term(&mut self) -> Result<Expr, ParseError> {
+        let position = self.position();
+        match self.tokens.get(self.current) {
+            Some((Token::Word(w), _)) => {
+                let word = w.clone();
+                self.current += 1;
+                match parse_predicate(&word, position)? {
+                    Some(predicate) => { Ok(predicate) }
+                    None => { Ok(Expr::Tag(word)) }
+                }
+            }
+            Some((Token::Quoted(w), _)) => {
+                let tag = w.clone();
+                self.current += 1;
+                Ok(Expr::Tag(tag))
+            }
+            Some((Token::LeftParen, _)) => {
+                self.current += 1;
+                let expr = self.or_expr()?;
+                match self.peek() {
+                    Some(Token::RightParen) => {
+                        self.current += 1;
+                        Ok(expr)
+                    }
+                    _ => { error("Expected a closing parenthesis", self.position()) }
+                }
+            }
+            Some((Token::RightParen, _)) => { error("Unexpected closing parenthesis", position) }
+            Some((Token::And, _)) | Some((Token::Or, _)) => { error("Expected a tag before this operator", position) }
+            Some((Token::Not, _)) => { error("Expected a tag", position) }
+            None => { error("Expected a tag at the end of the query", position) }
+        }
+    }
+}
+
+//Parses a query. An empty query is Ok(None) and matches every image
+pub fn parse(query: &str) -> Result<Option<Expr>, ParseError> {
+    let tokens = tokenize(query)?;
+    if tokens.is_empty() {
+        return Ok(None);
+    }
+
+    let mut parser = Parser {
+        tokens,
+        current: 0,
+        end: query.chars().count()
+    };
+    let expr = parser.or_expr()?;
+    if parser.current < parser.tokens.len() {
+        return error("Unexpected closing parenthesis", parser.position());
+    }
+    Ok(Some(expr))
+}
+
+//Narrows a query down to the images that actually carry tag, as opposed to ones that only have a tag implying it
+//This is what operations that add or remove image_tags rows need to count and select
+pub fn and_tag(expr: &Option<Expr>, tag: &str) -> Option<Expr> {
+    let tagged = Expr::ExactTag(String::from(tag));
+    match expr {
+        Some(e) => { Some(Expr::And(Box::new(e.clone()), Box::new(tagged))) }
+        None => { Some(tagged) }
+    }
+}
+
+//Compiles an expression into a condition on the images table along with the values to bind to its parameters
+pub fn to_sql(expr: &Option<Expr>) -> (String, Vec<sqlite::Value>) {
+    fn compile(expr: &Expr, sql: &mut String, params: &mut Vec<sqlite::Value>) {
+        match expr {
+            Expr::Tag(tag) | Expr::Descendants(tag) => {
+                //Every tag that leads to this one, as (tag_id, related_id) pairs
+                let edges = match expr {
+                    Expr::Descendants(_) => { "SELECT tag_id, implied_id AS related_id FROM tag_implications UNION ALL SELECT tag_id, parent_id FROM tag_parents" }
+                    _ => { "SELECT tag_id, implied_id AS related_id FROM tag_implications" }
+                };
+
+                //An alias matches the images that have the tag it stands for
+                sql.push_str(&format!("images.id IN (SELECT image_id FROM image_tags WHERE tag_id IN (
+                    WITH RECURSIVE matching(id) AS (
+                        SELECT COALESCE((SELECT tag_id FROM tag_aliases WHERE alias=?), (SELECT id FROM tags WHERE name=?))
+                        UNION SELECT edges.tag_id FROM ({}) AS edges JOIN matching ON edges.related_id=matching.id
+                    )
+                    SELECT id FROM matching
+                ))", edges));
+                params.push(sqlite::Value::String(tag.clone()));
+                params.push(sqlite::Value::String(tag.clone()));
+            }
+            Expr::ExactTag(tag) => {
+                sql.push_str("images.id IN (SELECT image_id FROM image_tags WHERE tag_id=COALESCE((SELECT tag_id FROM tag_aliases WHERE alias=?), (SELECT id FROM tags WHERE name=?)))");
+                params.push(sqlite::Value::String(tag.clone()));
+                params.push(sqlite::Value::String(tag.clone()));
+            }
+            Expr::Compare(field, comparison, value) => {
+                let column = match field {
+                    Field::Width => { "images.width" }
+                    Field::Height => { "images.height" }
+                    Field::Size => { "images.size" }
+                    Field::Ratio => { "(CAST(images.width AS REAL) / images.height)" }
+                    Field::TagCount => { "(SELECT COUNT(*) FROM image_tags WHERE image_tags.image_id=images.id)" }
+                    Field::Imported => { "images.imported" }
+                    Field::Modified => { "images.modified" }
+                    Field::ImportedAge => { "(strftime('%s', 'now') - images.imported)" }
+                    Field::ModifiedAge => { "(strftime('%s', 'now') - images.modified)" }
+                };
+                let op = match comparison {
+                    Comparison::Less => { "<" }
+                    Comparison::LessEqual => { "<=" }
+                    Comparison::Equal => { "=" }
+                    Comparison::GreaterEqual => { ">=" }
+                    Comparison::Greater => { ">" }
+                };
+
+                match (field, comparison) {
+                    //Ratios are never exactly equal after a resize, so allow a little slack
+                    (Field::Ratio, Comparison::Equal) => { sql.push_str(&format!("ABS({} - ?) < 0.01", column)); }
+
+                    //Dates are turned into timestamps by sqlite, and stand for the whole day rather than its first second
+                    (Field::Imported, comparison) | (Field::Modified, comparison) => {
+                        let start = "strftime('%s', ?)";
+                        let end = "strftime('%s', ?, '+1 day')";
+                        match comparison {
+                            Comparison::Less => { sql.push_str(&format!("{} < {}", column, start)); }
+                            Comparison::LessEqual => { sql.push_str(&format!("{} < {}", column, end)); }
+                            Comparison::Equal => {
+                                sql.push_str(&format!("({} >= {} AND {} < {})", column, start, column, end));
+                                params.push(value.clone());
+                            }
+                            Comparison::GreaterEqual => { sql.push_str(&format!("{} >= {}", column, start)); }
+                            Comparison::Greater => { sql.push_str(&format!("{} >= {}", column, end)); }
+                        }
+                    }
+
+                    _ => { sql.push_str(&format!("{} {} ?", column, op)); }
+                }
+                params.push(value.clone());
+            }
+            Expr::Format(format) => {
+                sql.push_str("images.format=?");
+                params.push(sqlite::Value::String(format.clone()));
+            }
+            Expr::Not(e) => {
+                sql.push_str("NOT (");
+                compile(e, sql, params);
+                sql.push(')');
+            }
+            Expr::And(a, b) | Expr::Or(a, b) => {
+                let op = if let Expr::And(..) = expr { " AND " } else { " OR " };
+                sql.push('(');
+                compile(a, sql, params);
+                sql.push_str(op);
+                compile(b, sql, params);
+                sql.push(')');
+            }
+        }
+    }
+
+    let mut sql = String::new();
+    let mut params = Vec::new();
+    match expr {
+        Some(e) => { compile(e, &mut sql, &mut params); }
+        None => { sql.push('1'); }
+    }
+    (sql, params)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    //Writes an expression out with explicit grouping so that the shape of the parse can be compared as a string
+    fn show(expr: &Expr) -> String {
+        match expr {
+            Expr::Tag(tag) => { tag.clone() }
+            Expr::ExactTag(tag) => { format!("={}", tag) }
+            Expr::Descendants(tag) => { format!("under {}", tag) }
+            Expr::Compare(field, comparison, value) => {
+                let field = match field {
+                    Field::Width => { "width" }
+                    Field::Height => { "height" }
+                    Field::Size => { "size" }
+                    Field::Ratio => { "ratio" }
+                    Field::TagCount => { "tagcount" }
+                    Field::Imported => { "imported" }
+                    Field::Modified => { "modified" }
+                    Field::ImportedAge => { "imported_age" }
+                    Field::ModifiedAge => { "modified_age" }
+                };
+                let op = match comparison {
+                    Comparison::Less => { "<" }
+                    Comparison::LessEqual => { "<=" }
+                    Comparison::Equal => { "=" }
+                    Comparison::GreaterEqual => { ">=" }
+                    Comparison::Greater => { ">" }
+                };
+                let value = match value {
+                    sqlite::Value::Integer(n) => { n.to_string() }
+                    sqlite::Value::Float(f) => { format!("{:.3}", f) }
+                    sqlite::Value::String(s) => { s.clone() }
+                    _ => { String::from("?") }
+                };
+                format!("{} {} {}", field, op, value)
+            }
+            Expr::Format(format) => { format!("format {}", format) }
+            Expr::Not(e) => { format!("(not {})", show(e)) }
+            Expr::And(a, b) => { format!("(and {} {})", show(a), show(b)) }
+            Expr::Or(a, b) => { format!("(or {} {})", show(a), show(b)) }
+        }
+    }
+
+    fn parsed(query: &str) -> String {
+        match parse(query) {
+            Ok(Some(expr)) => { show(&expr) }
+            Ok(None) => { String::new() }
+            Err(e) => { format!("error at {}: {}", e.position, e.message) }
+        }
+    }
+
+    #[test]
+    fn precedence() {
+        assert_eq!(parsed("fox_girl AND (smug OR gyaru) AND NOT sketch"), "(and (and fox_girl (or smug gyaru)) (not sketch))");
+        assert_eq!(parsed("a b OR c"), "(or (and a b) c)");
+        assert_eq!(parsed("NOT NOT a"), "(not (not a))");
+    }
+
+    #[test]
+    fn operators_and_quotes() {
+        assert_eq!(parsed("a or \"and\""), "(or a and)");
+        assert_eq!(parsed("\"two words\" not b"), "(and two words (not b))");
+        assert_eq!(parsed("   "), "");
+    }
+
+    #[test]
+    fn errors_point_at_the_problem() {
+        assert_eq!(parsed("\"abc"), "error at 0: Unterminated quote");
+        assert_eq!(parsed("a (b"), "error at 4: Expected a closing parenthesis");
+        assert_eq!(parsed("a)"), "error at 1: Unexpected closing parenthesis");
+        assert_eq!(parsed("AND a"), "error at 0: Expected a tag before this operator");
+        assert_eq!(parsed("a NOT"), "error at 5: Expected a tag at the end of the query");
+    }
+
+    #[test]
+    fn metadata_predicates() {
+        assert_eq!(parsed("width:>=1920 height:<1080"), "(and width >= 1920 height < 1080)");
+        assert_eq!(parsed("size:2mb"), "size = 2097152");
+        assert_eq!(parsed("ratio:16:9"), "ratio = 1.778");
+        assert_eq!(parsed("ratio:portrait"), "ratio < 1.000");
+        assert_eq!(parsed("imported:<7d"), "imported_age < 604800");
+        assert_eq!(parsed("modified:>=2021-05-01"), "modified >= 2021-05-01");
+        assert_eq!(parsed("format:JPG"), "format jpeg");
+        assert_eq!(parsed("re:zero"), "re:zero");
+        assert_eq!(parsed("under:animal_ears"), "under animal_ears");
+        assert_eq!(parsed("width:big"), "error at 6: Expected a width in pixels");
+    }
+
+    #[test]
+    fn and_tag_requires_the_tag_itself() {
+        let expr = match parse("a OR b") {
+            Ok(e) => { e }
+            Err(e) => { panic!("{}", e); }
+        };
+        assert_eq!(show(&and_tag(&expr, "c").unwrap()), "(and (or a b) =c)");
+        assert_eq!(show(&and_tag(&None, "c").unwrap()), "=c");
+    }
+}
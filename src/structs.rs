@@ -1,26 +1,88 @@
 use gl::types::*;
+use image::RgbaImage;
 use imgui::ImString;
-use ozy::glutil;
-use ozy::structs::ImageData;
-use std::sync::mpsc::Sender;
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, BinaryHeap};
+use std::panic;
+use std::sync::{Arc, Condvar, Mutex};
+use std::sync::atomic::{self, AtomicU64};
+use std::sync::mpsc::{self, Receiver};
+use std::thread;
 
 use crate::*;
+use crate::library::ImageMetadata;
+
+//One decoded frame of an image. Still images have exactly one
+pub struct Frame {
+    pub pixels: RgbaImage,
+    pub delay: f32                  //Seconds the frame is shown for before the next one
+}
+
+//GL textures of an image, one per frame
+pub struct Texture {
+    pub frames: Vec<GLuint>,
+    pub delays: Vec<f32>,           //Seconds each frame is shown for
+    pub width: u32,
+    pub height: u32
+}
+
+impl Texture {
+    pub fn upload(frames: &[Frame]) -> Self {
+        Texture {
+            frames: frames.iter().map(|f| unsafe { upload_rgba_texture(f.pixels.width(), f.pixels.height(), &f.pixels) }).collect(),
+            delays: frames.iter().map(|f| f.delay).collect(),
+            width: frames[0].pixels.width(),
+            height: frames[0].pixels.height()
+        }
+    }
+
+    pub fn is_animated(&self) -> bool {
+        self.frames.len() > 1
+    }
+
+    //The texture to draw once the animation has been playing for time seconds
+    pub fn frame_at(&self, time: f32) -> GLuint {
+        self.frames[animation::frame_index(&self.delays, time)]
+    }
+
+    //Bytes of texture memory used by all of the frames
+    pub fn bytes(&self) -> usize {
+        self.width as usize * self.height as usize * 4 * self.frames.len()
+    }
+}
+
+impl Drop for Texture {
+    fn drop(&mut self) {
+        unsafe { gl::DeleteTextures(self.frames.len() as GLsizei, self.frames.as_ptr()); }
+    }
+}
 
 //Stores all the state required for an image the program has loaded
+//The grid draws gl_name, which is a thumbnail that's only resident while the image is near the scroll viewport
+//The full resolution texture is only loaded while the image is selected
 pub struct OpenImage {
-    pub name: String,				//Just the filename with extension
+    pub name: String,				//Filename with extension that the image is stored under in the image directory
     pub orignal_path: String,       //The original path the image was opened from
+    pub hash: String,               //SHA-256 of the file's contents. This is the image's identity in the database
+    pub metadata: ImageMetadata,    //Intrinsic file metadata as stored in the database
     pub tags: Vec<ImString>,		//Array of tags
-    pub gl_name: GLuint,			//GL texture
-    pub width: usize,				//Image width in pixels
-    pub height: usize				//Image height in pixels
+    pub gl_name: Option<Texture>,	//GL texture of the thumbnail, if it's resident
+    pub thumbnail_requested: bool,  //Whether the thumbnail has been queued on the loader thread to become resident again
+    pub last_displayed: f32,        //Value of elapsed_time when the image was last drawn in the grid
+    pub original: Option<Texture>,  //GL texture of the full resolution image, if it's loaded
+    pub original_requested: bool,   //Whether the full resolution image has been queued on the loader thread
+    pub animation_time: f32,        //How long the animation has been playing for, in seconds
+    pub paused: bool,               //Whether the animation is stopped on its current frame
+    pub width: usize,				//Original image width in pixels
+    pub height: usize				//Original image height in pixels
 }
 
 impl OpenImage {
-    pub fn from_imagedata(image_data: ImageData, path: String) -> Self {
-        let height = image_data.height;
-        let width = image_data.width;
-        let gl_name = unsafe { glutil::load_texture_from_data(image_data, &DEFAULT_TEX_PARAMS) };
+    //frames are the thumbnail, metadata describes the original
+    pub fn from_frames(frames: &[Frame], path: String, hash: String, metadata: ImageMetadata) -> Self {
+        let gl_name = Some(Texture::upload(frames));
+        let width = metadata.width;
+        let height = metadata.height;
         
         let name = {
             let p = Path::new(&path);
@@ -30,36 +92,704 @@ impl OpenImage {
         OpenImage {
             name,
             orignal_path: path,
+            hash,
+            metadata,
             tags: Vec::new(),
             gl_name,
+            thumbnail_requested: false,
+            last_displayed: 0.0,
+            original: None,
+            original_requested: false,
+            animation_time: 0.0,
+            paused: false,
             width: width as usize,
             height: height as usize 
         }
     }
+
+    pub fn set_thumbnail(&mut self, frames: &[Frame]) {
+        self.gl_name = Some(Texture::upload(frames));
+    }
+
+    //Bytes of texture memory used by the resident thumbnail
+    pub fn thumbnail_bytes(&self) -> usize {
+        match &self.gl_name {
+            Some(tex) => { tex.bytes() }
+            None => { 0 }
+        }
+    }
+
+    //Bytes of texture memory used by all of this image's resident textures
+    pub fn texture_bytes(&self) -> usize {
+        let original = match &self.original {
+            Some(tex) => { tex.bytes() }
+            None => { 0 }
+        };
+        self.thumbnail_bytes() + original
+    }
+
+    //Frees the thumbnail texture, leaving just the image's metadata
+    pub fn release_thumbnail(&mut self) {
+        self.gl_name = None;
+        self.thumbnail_requested = false;
+    }
+
+    pub fn set_original(&mut self, frames: &[Frame]) {
+        self.original = Some(Texture::upload(frames));
+    }
+
+    //Frees the full resolution texture
+    pub fn release_original(&mut self) {
+        self.original = None;
+        self.original_requested = false;
+    }
+
+    //The texture to draw for this image right now: the original if it's loaded, otherwise the thumbnail
+    //Both have the same frame timings, so switching between them doesn't make the animation jump
+    pub fn current_frame(&self, prefer_original: bool) -> Option<GLuint> {
+        let texture = match (prefer_original, &self.original) {
+            (true, Some(tex)) => { Some(tex) }
+            _ => { self.gl_name.as_ref() }
+        };
+        texture.map(|tex| tex.frame_at(self.animation_time))
+    }
+
+    pub fn is_animated(&self) -> bool {
+        self.gl_name.as_ref().or(self.original.as_ref()).map_or(false, |tex| tex.is_animated())
+    }
 }
 
-impl Drop for OpenImage {
-    fn drop(&mut self) {
-        unsafe { gl::DeleteTextures(1, &mut self.gl_name); }
+//A grid entry whose file couldn't be loaded. It's shown as an error tile until the user deals with it
+pub struct FailedImage {
+    pub path: String,               //Path the load was attempted from
+    pub name: Option<String>,       //Name of the library row the file belongs to, if it's in the library
+    pub hash: Option<String>,       //Hash of that row, if it has one
+    pub reason: String              //Error message from the loader
+}
+
+//Zoom limits of the viewer, in screen pixels per image pixel
+pub const MIN_ZOOM: f32 = 0.01;
+pub const MAX_ZOOM: f32 = 32.0;
+
+//State of the full size viewer window
+pub struct Viewer {
+    pub index: usize,               //Index into open_images of the image being viewed
+    pub hash: String,               //Hash of that image, so the viewer can follow it if open_images changes
+    pub zoom: f32,                  //Screen pixels per image pixel
+    pub offset: [f32; 2],           //Position of the image's top left corner relative to the top left of the canvas
+    pub fit: bool                   //Whether the image is kept fit to the canvas. Zooming or panning turns this off
+}
+
+impl Viewer {
+    pub fn new(index: usize, hash: String) -> Self {
+        Viewer {
+            index,
+            hash,
+            zoom: 1.0,
+            offset: [0.0, 0.0],
+            fit: true
+        }
+    }
+
+    //Switches to another image, which starts out fit to the canvas
+    pub fn show(&mut self, index: usize, hash: String) {
+        self.index = index;
+        self.hash = hash;
+        self.fit = true;
+    }
+
+    //Scales the image to fill as much of the canvas as it can without cropping, and centers it
+    pub fn fit_to(&mut self, image_size: [f32; 2], canvas_size: [f32; 2]) {
+        self.zoom = f32::min(canvas_size[0] / image_size[0], canvas_size[1] / image_size[1]).clamp(MIN_ZOOM, MAX_ZOOM);
+        for i in 0..2 {
+            self.offset[i] = (canvas_size[i] - image_size[i] * self.zoom) / 2.0;
+        }
+    }
+
+    //Changes the zoom level while keeping the point of the image under anchor where it is
+    //anchor is relative to the top left of the canvas
+    pub fn zoom_around(&mut self, zoom: f32, anchor: [f32; 2]) {
+        let zoom = zoom.clamp(MIN_ZOOM, MAX_ZOOM);
+        for i in 0..2 {
+            let image_point = (anchor[i] - self.offset[i]) / self.zoom;
+            self.offset[i] = anchor[i] - image_point * zoom;
+        }
+        self.zoom = zoom;
+        self.fit = false;
+    }
+
+    pub fn pan(&mut self, delta: [f32; 2]) {
+        self.offset[0] += delta[0];
+        self.offset[1] += delta[1];
+        self.fit = false;
+    }
+}
+
+//A fullscreen slideshow over a fixed list of images
+//One slide is preloaded at a time, and the slide being replaced is kept around until the crossfade finishes
+pub struct Slideshow {
+    pub paths: Vec<String>,                 //Every image in the show, in the order they're shown
+    pub position: usize,                    //Index into paths of the current slide
+    pub current: Option<Texture>,
+    pub previous: Option<Texture>,          //Fades out underneath the current slide
+    pub next: Option<Texture>,              //The preloaded upcoming slide
+    pub wanted: usize,                      //Index into paths of the slide that next holds or is waiting for
+    pub requested: Option<(usize, u64)>,    //The slide that's been asked for and the loader generation it was asked for in
+    pub failures: usize,                    //Slides skipped in a row because they couldn't be loaded
+    pub shown_at: f32,                      //Value of elapsed_time when the current slide appeared
+    pub skipping: bool,                     //Show the next slide as soon as it's loaded instead of waiting out the interval
+    pub paused: bool
+}
+
+impl Slideshow {
+    pub fn new(paths: Vec<String>) -> Self {
+        Slideshow {
+            paths,
+            position: 0,
+            current: None,
+            previous: None,
+            next: None,
+            wanted: 0,
+            requested: None,
+            failures: 0,
+            shown_at: 0.0,
+            skipping: true,
+            paused: false
+        }
+    }
+
+    fn wrap(&self, index: i64) -> usize {
+        index.rem_euclid(self.paths.len() as i64) as usize
+    }
+
+    //Path of the slide that should be loaded next, if it hasn't already been asked for
+    //Requests are made again if the loader has cancelled everything since
+    pub fn needs_request(&mut self, generation: u64) -> Option<String> {
+        let nothing_else_to_show = self.paths.len() == 1 && self.current.is_some();
+        if self.next.is_some() || self.requested == Some((self.wanted, generation)) || self.failures >= self.paths.len() || nothing_else_to_show {
+            return None;
+        }
+        self.requested = Some((self.wanted, generation));
+        Some(self.paths[self.wanted].clone())
+    }
+
+    pub fn receive(&mut self, path: &str, frames: &[Frame]) {
+        if self.next.is_none() && self.paths[self.wanted] == path {
+            self.next = Some(Texture::upload(frames));
+            self.failures = 0;
+        }
+    }
+
+    //Slides that can't be loaded are skipped over
+    pub fn fail(&mut self, path: &str) {
+        if self.paths[self.wanted] == path {
+            self.failures += 1;
+            self.wanted = self.wrap(self.wanted as i64 + 1);
+            self.requested = None;
+        }
+    }
+
+    //Moves step slides from the current one, as soon as that slide is loaded
+    pub fn skip(&mut self, step: i64) {
+        let wanted = self.wrap(self.position as i64 + step);
+        if wanted != self.wanted {
+            self.wanted = wanted;
+            self.next = None;
+            self.requested = None;
+        }
+        self.skipping = true;
+    }
+
+    //Whether it's time to swap in the preloaded slide
+    pub fn should_advance(&self, now: f32, interval: f32) -> bool {
+        self.next.is_some() && (self.skipping || (!self.paused && now - self.shown_at >= interval))
+    }
+
+    pub fn advance(&mut self, now: f32) {
+        self.previous = self.current.take();
+        self.current = self.next.take();
+        self.position = self.wanted;
+        self.wanted = self.wrap(self.position as i64 + 1);
+        self.requested = None;
+        self.shown_at = now;
+        self.skipping = false;
+    }
+}
+
+//The library query whose results are shown in the gallery
+pub struct View {
+    pub expr: Option<query::Expr>,      //None shows every image
+    pub ids: Vec<i64>,                  //Every result in order, taken when the view was refreshed so that tagging can't reshuffle the pages
+    pub loaded: usize                   //How many results have been queued so far. Also the index in ids of the next page
+}
+
+impl View {
+    pub fn new(expr: Option<query::Expr>) -> Self {
+        View {
+            expr,
+            ids: Vec::new(),
+            loaded: 0
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum LoadKind {
+    Thumbnail,                      //A new entry for the grid
+    Reload,                         //The thumbnail of an open image that was scrolled far away and is coming back
+    Original,                       //The full resolution texture of an image that's already open
+    Slide                           //The full resolution texture of a slideshow image, which needn't be open
+}
+
+//A request for the loader thread
+pub struct LoadRequest {
+    pub path: String,
+    pub hash: Option<String>,       //Hash of the file if the library already knows it, which lets cached thumbnails be used
+    pub metadata: Option<ImageMetadata>,    //What the library recorded about the file. Cached thumbnails aren't used without it
+    pub thumbnail_dir: String,
+    pub kind: LoadKind,
+    pub index: usize,               //For LoadKind::Thumbnail, how many grid entries were queued before this one since the gallery was cleared
+    pub generation: u64             //The view this request was made for. Requests from older views are dropped
+}
+
+//Everything the loader thread computes for one image
+//A thumbnail from the cache skips decoding the original, so it has no perceptual hash and its metadata is the library's
+pub struct LoadedImage {
+    pub frames: Vec<Frame>,         //Always at least one
+    pub hash: String,               //SHA-256 of the file
+    pub phash: Option<u64>,         //Perceptual hash of the pixels
+    pub metadata: ImageMetadata,
+    pub cached: bool                //Whether the frames came from the thumbnail cache
+}
+
+pub type LoadResult = (LoadRequest, Result<LoadedImage, String>);
+
+//How urgently a request should be serviced. Higher priorities are taken off the queue first
+pub const PRIORITY_PREFETCH: i32 = 0;       //Thumbnails for rows just off screen
+pub const PRIORITY_PAGE: i32 = 1;           //New grid entries from the next page of results
+pub const PRIORITY_VISIBLE: i32 = 2;        //Thumbnails for rows that are on screen right now
+pub const PRIORITY_ORIGINAL: i32 = 3;       //The full resolution texture of the selected image
+
+struct QueuedRequest {
+    priority: i32,
+    sequence: u64,                  //Requests of equal priority are serviced in the order they were queued
+    request: LoadRequest
+}
+
+impl PartialEq for QueuedRequest {
+    fn eq(&self, other: &Self) -> bool {
+        self.priority == other.priority && self.sequence == other.sequence
+    }
+}
+
+impl Eq for QueuedRequest {}
+
+impl PartialOrd for QueuedRequest {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for QueuedRequest {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.priority.cmp(&other.priority).then_with(|| other.sequence.cmp(&self.sequence))
     }
 }
 
-//Represents the current state of the image loading thread
-pub struct LoaderThread {
-    pub images_in_flight: usize,
-    pub sender: Sender<String>
+#[derive(Default)]
+struct RequestQueue {
+    heap: BinaryHeap<QueuedRequest>,
+    next_sequence: u64
 }
 
-impl LoaderThread {
-    pub fn new(sender: Sender<String>) -> Self {
-        LoaderThread {
+//Client-side tracking of the pool of threads that load image data from disk
+pub struct LoaderPool {
+    pub images_in_flight: usize,    //Number of grid entries that have been queued but not received
+    pub thumbnail_dir: String,      //Where the loader threads find and cache thumbnails
+    next_index: usize,              //Index the next grid entry to be queued will get
+    next_result: usize,             //Index of the grid entry that has to be received before any later ones are handed out
+    held_results: BTreeMap<usize, LoadResult>,      //Grid entries that finished before an earlier one did
+    generation: Arc<AtomicU64>,     //Bumped whenever the gallery is cleared. Shared with the workers so they can skip stale requests
+    queue: Arc<(Mutex<RequestQueue>, Condvar)>
+}
+
+impl LoaderPool {
+    //Starts worker_count loader threads, returning the pool and the receiving end of their results
+    //The result queue is bounded so that decoded images can't pile up faster than the main thread uploads them
+    pub fn spawn(worker_count: usize, thumbnail_dir: String) -> (Self, Receiver<LoadResult>) {
+        let (result_tx, result_rx) = mpsc::sync_channel::<LoadResult>(worker_count * 2);
+        let queue = Arc::new((Mutex::new(RequestQueue::default()), Condvar::new()));
+        let generation = Arc::new(AtomicU64::new(0));
+
+        for _ in 0..worker_count {
+            let queue = Arc::clone(&queue);
+            let generation = Arc::clone(&generation);
+            let result_tx = result_tx.clone();
+            thread::spawn(move || {
+                let (lock, condvar) = &*queue;
+                loop {
+                    //The lock is only held while waiting for a request, not while servicing it
+                    let request = {
+                        let mut q = match lock.lock() {
+                            Ok(q) => { q }
+                            Err(_) => { break; }
+                        };
+                        loop {
+                            if let Some(queued) = q.heap.pop() {
+                                break queued.request;
+                            }
+                            q = match condvar.wait(q) {
+                                Ok(q) => { q }
+                                Err(_) => { return; }
+                            };
+                        }
+                    };
+
+                    //Anything queued for a view that has since been replaced is skipped without being decoded
+                    if request.generation != generation.load(atomic::Ordering::SeqCst) {
+                        continue;
+                    }
+
+                    //A decoder panicking on a malformed file shouldn't take the worker down with it
+                    let loaded = match panic::catch_unwind(|| service_request(&request)) {
+                        Ok(result) => { result }
+                        Err(_) => { Err(String::from("the decoder crashed on this file")) }
+                    };
+                    if let Err(e) = result_tx.send((request, loaded)) {
+                        println!("{}", e);
+                        break;
+                    }
+                }
+            });
+        }
+
+        let pool = LoaderPool {
             images_in_flight: 0,
-            sender
+            thumbnail_dir,
+            next_index: 0,
+            next_result: 0,
+            held_results: BTreeMap::new(),
+            generation,
+            queue
+        };
+        (pool, result_rx)
+    }
+
+    pub fn generation(&self) -> u64 {
+        self.generation.load(atomic::Ordering::SeqCst)
+    }
+
+    //Drops every queued request and marks anything currently being decoded as stale
+    //Called whenever the gallery is cleared, so that loads for the old view stop competing with the new one
+    pub fn cancel_all(&mut self) {
+        self.generation.fetch_add(1, atomic::Ordering::SeqCst);
+        match self.queue.0.lock() {
+            Ok(mut q) => { q.heap.clear(); }
+            Err(e) => { println!("{}", e); }
         }
+        self.images_in_flight = 0;
+        self.next_index = 0;
+        self.next_result = 0;
+        self.held_results.clear();
     }
 
-    pub fn queue_image(&mut self, path: String) {
-        send_or_error(&self.sender, path);
+    //Grid entries are handed out by receive() in the order they're queued here, so the grid keeps the order of the query
+    pub fn queue_image(&mut self, path: String, hash: Option<String>, metadata: Option<ImageMetadata>) {
+        self.send(path, hash, metadata, LoadKind::Thumbnail, self.next_index, PRIORITY_PAGE);
+        self.next_index += 1;
         self.images_in_flight += 1;
     }
-}
\ No newline at end of file
+
+    //Reloads and full resolution loads don't count towards images_in_flight as they don't add anything to the grid
+    pub fn queue_reload(&mut self, path: String, hash: String, metadata: ImageMetadata, priority: i32) {
+        self.send(path, Some(hash), Some(metadata), LoadKind::Reload, 0, priority);
+    }
+
+    pub fn queue_original(&mut self, path: String, hash: String) {
+        self.send(path, Some(hash), None, LoadKind::Original, 0, PRIORITY_ORIGINAL);
+    }
+
+    pub fn queue_slide(&mut self, path: String) {
+        self.send(path, None, None, LoadKind::Slide, 0, PRIORITY_ORIGINAL);
+    }
+
+    //The next result from the workers that's ready to be used, if there is one
+    //The workers finish grid entries in whatever order they like, so one that comes back early is held until every entry before it has arrived
+    //Everything else, including results for a view that has since been cleared, is passed straight through
+    pub fn receive(&mut self, results: &Receiver<LoadResult>) -> Option<LoadResult> {
+        loop {
+            if let Some(result) = self.held_results.remove(&self.next_result) {
+                self.next_result += 1;
+                return Some(result);
+            }
+
+            let result = match results.try_recv() {
+                Ok(r) => { r }
+                Err(_) => { return None; }
+            };
+            let request = &result.0;
+            if request.kind != LoadKind::Thumbnail || request.generation != self.generation() {
+                return Some(result);
+            }
+            self.held_results.insert(request.index, result);
+        }
+    }
+
+    fn send(&self, path: String, hash: Option<String>, metadata: Option<ImageMetadata>, kind: LoadKind, index: usize, priority: i32) {
+        let request = LoadRequest {
+            path,
+            hash,
+            metadata,
+            thumbnail_dir: self.thumbnail_dir.clone(),
+            kind,
+            index,
+            generation: self.generation()
+        };
+
+        let (lock, condvar) = &*self.queue;
+        match lock.lock() {
+            Ok(mut q) => {
+                let sequence = q.next_sequence;
+                q.next_sequence += 1;
+                q.heap.push(QueuedRequest {
+                    priority,
+                    sequence,
+                    request
+                });
+                condvar.notify_one();
+            }
+            Err(e) => { println!("{}", e); }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    //A pool with no workers, so that the test can play their part
+    fn pool() -> LoaderPool {
+        LoaderPool::spawn(0, String::from("thumbs")).0
+    }
+
+    //Takes everything off the pool's queue, highest priority first
+    fn take_requests(pool: &LoaderPool) -> Vec<LoadRequest> {
+        let mut q = pool.queue.0.lock().unwrap();
+        let mut requests = Vec::new();
+        while let Some(queued) = q.heap.pop() {
+            requests.push(queued.request);
+        }
+        requests
+    }
+
+    fn received_path(pool: &mut LoaderPool, results: &Receiver<LoadResult>) -> Option<String> {
+        pool.receive(results).map(|(request, _)| { request.path })
+    }
+
+    #[test]
+    fn grid_entries_are_handed_out_in_queue_order() {
+        let mut pool = pool();
+        for path in &["a.png", "b.png", "c.png"] {
+            pool.queue_image(String::from(*path), None, None);
+        }
+        let mut requests = take_requests(&pool);
+        assert_eq!(requests.iter().map(|r| { r.index }).collect::<Vec<usize>>(), vec![0, 1, 2]);
+
+        let (tx, rx) = mpsc::channel();
+        let c = requests.pop().unwrap();
+        let b = requests.pop().unwrap();
+        let a = requests.pop().unwrap();
+
+        //c and b finish before a, so nothing can be handed out yet
+        tx.send((c, Err(String::from("c")))).unwrap();
+        assert_eq!(received_path(&mut pool, &rx), None);
+        tx.send((b, Err(String::from("b")))).unwrap();
+        assert_eq!(received_path(&mut pool, &rx), None);
+
+        tx.send((a, Err(String::from("a")))).unwrap();
+        assert_eq!(received_path(&mut pool, &rx).as_deref(), Some("a.png"));
+        assert_eq!(received_path(&mut pool, &rx).as_deref(), Some("b.png"));
+        assert_eq!(received_path(&mut pool, &rx).as_deref(), Some("c.png"));
+        assert_eq!(received_path(&mut pool, &rx), None);
+    }
+
+    #[test]
+    fn other_results_are_not_held() {
+        let mut pool = pool();
+        pool.queue_image(String::from("a.png"), None, None);
+        pool.queue_image(String::from("b.png"), None, None);
+        let mut requests = take_requests(&pool);
+        let b = requests.pop().unwrap();
+        let a = requests.pop().unwrap();
+
+        //A full resolution load doesn't wait for the grid entry queued before it
+        pool.queue_original(String::from("original.png"), String::from("hash"));
+        let original = take_requests(&pool).pop().unwrap();
+        let (tx, rx) = mpsc::channel();
+        tx.send((b, Err(String::new()))).unwrap();
+        tx.send((original, Err(String::new()))).unwrap();
+        assert_eq!(received_path(&mut pool, &rx).as_deref(), Some("original.png"));
+
+        //Once the gallery is cleared, a late result from the old view is passed through for the caller to discard
+        pool.cancel_all();
+        tx.send((a, Err(String::new()))).unwrap();
+        assert_eq!(received_path(&mut pool, &rx).as_deref(), Some("a.png"));
+
+        //The new view's entries are numbered from zero again
+        pool.queue_image(String::from("d.png"), None, None);
+        let d = take_requests(&pool).pop().unwrap();
+        assert_eq!(d.index, 0);
+        tx.send((d, Err(String::new()))).unwrap();
+        assert_eq!(received_path(&mut pool, &rx).as_deref(), Some("d.png"));
+    }
+
+    #[test]
+    fn fitting_centers_the_scaled_image() {
+        let mut viewer = Viewer::new(0, String::from("hash"));
+        viewer.fit_to([200.0, 100.0], [400.0, 400.0]);
+        assert_eq!(viewer.zoom, 2.0);
+        assert_eq!(viewer.offset, [0.0, 100.0]);
+        assert!(viewer.fit);
+
+        //Tiny canvases don't push the zoom below its limit
+        viewer.fit_to([10000.0, 10000.0], [1.0, 1.0]);
+        assert_eq!(viewer.zoom, MIN_ZOOM);
+    }
+
+    #[test]
+    fn zooming_keeps_the_anchor_still() {
+        let mut viewer = Viewer::new(0, String::from("hash"));
+        viewer.zoom_around(2.0, [100.0, 50.0]);
+        assert_eq!(viewer.zoom, 2.0);
+        assert_eq!(viewer.offset, [-100.0, -50.0]);
+        assert!(!viewer.fit);
+
+        //The image pixel under the anchor is still under it after zooming back out
+        viewer.pan([30.0, 20.0]);
+        viewer.zoom_around(0.5, [130.0, 70.0]);
+        assert_eq!(viewer.offset, [80.0, 45.0]);
+        assert_eq!((130.0 - viewer.offset[0]) / viewer.zoom, 100.0);
+        assert_eq!((70.0 - viewer.offset[1]) / viewer.zoom, 50.0);
+    }
+
+    #[test]
+    fn zoom_is_clamped() {
+        let mut viewer = Viewer::new(0, String::from("hash"));
+        viewer.zoom_around(1000.0, [0.0, 0.0]);
+        assert_eq!(viewer.zoom, MAX_ZOOM);
+        viewer.zoom_around(0.0, [0.0, 0.0]);
+        assert_eq!(viewer.zoom, MIN_ZOOM);
+        assert_eq!(viewer.offset, [0.0, 0.0]);
+    }
+
+    //Stands in for an uploaded slide. There's no GL context, so deleting it is made a no-op
+    fn texture() -> Texture {
+        extern "system" fn delete_textures(_: GLsizei, _: *const GLuint) {}
+        gl::DeleteTextures::load_with(|_| { delete_textures as *const std::os::raw::c_void });
+        Texture {
+            frames: vec![1],
+            delays: vec![0.0],
+            width: 1,
+            height: 1
+        }
+    }
+
+    fn slideshow() -> Slideshow {
+        Slideshow::new(vec![String::from("a.png"), String::from("b.png"), String::from("c.png")])
+    }
+
+    #[test]
+    fn slides_are_requested_once_per_generation() {
+        let mut show = slideshow();
+        assert_eq!(show.needs_request(0).as_deref(), Some("a.png"));
+        assert_eq!(show.needs_request(0), None);
+
+        //The loader was cancelled, so the request has to be made again
+        assert_eq!(show.needs_request(1).as_deref(), Some("a.png"));
+        assert_eq!(show.needs_request(1), None);
+
+        //Nothing more is asked for while a slide is waiting to be shown
+        show.next = Some(texture());
+        show.requested = None;
+        assert_eq!(show.needs_request(1), None);
+
+        //A lone image is only loaded once
+        let mut show = Slideshow::new(vec![String::from("a.png")]);
+        show.next = Some(texture());
+        show.advance(0.0);
+        assert_eq!(show.needs_request(0), None);
+    }
+
+    #[test]
+    fn failed_slides_are_skipped() {
+        let mut show = slideshow();
+        show.needs_request(0);
+
+        //A failure for some other slide is ignored
+        show.fail("b.png");
+        assert_eq!(show.wanted, 0);
+        assert_eq!(show.failures, 0);
+
+        show.fail("a.png");
+        assert_eq!(show.needs_request(0).as_deref(), Some("b.png"));
+        show.fail("b.png");
+        assert_eq!(show.needs_request(0).as_deref(), Some("c.png"));
+
+        //Once every slide has failed in a row, there's nothing left to try
+        show.fail("c.png");
+        assert_eq!(show.wanted, 0);
+        assert_eq!(show.needs_request(0), None);
+    }
+
+    #[test]
+    fn slides_advance_after_the_interval() {
+        let mut show = slideshow();
+        assert!(!show.should_advance(100.0, 5.0));
+
+        //The first slide is shown as soon as it's loaded
+        show.next = Some(texture());
+        assert!(show.should_advance(0.0, 5.0));
+        show.advance(0.0);
+        assert_eq!(show.position, 0);
+        assert_eq!(show.wanted, 1);
+        assert!(show.current.is_some());
+        assert!(show.next.is_none());
+        assert!(!show.should_advance(100.0, 5.0));
+
+        show.next = Some(texture());
+        assert!(!show.should_advance(4.0, 5.0));
+        assert!(show.should_advance(5.0, 5.0));
+        show.paused = true;
+        assert!(!show.should_advance(10.0, 5.0));
+        show.paused = false;
+
+        show.advance(5.0);
+        assert_eq!(show.position, 1);
+        assert_eq!(show.wanted, 2);
+        assert_eq!(show.shown_at, 5.0);
+        assert!(show.previous.is_some());
+    }
+
+    #[test]
+    fn skipping_wraps_around() {
+        let mut show = slideshow();
+        show.next = Some(texture());
+        show.advance(0.0);
+        show.next = Some(texture());
+
+        //Going back from the first slide lands on the last, and the preloaded slide is thrown away
+        show.skip(-1);
+        assert_eq!(show.wanted, 2);
+        assert!(show.next.is_none());
+        assert_eq!(show.needs_request(0).as_deref(), Some("c.png"));
+
+        //Skipping to the slide that's already wanted keeps it
+        show.next = Some(texture());
+        show.skip(2);
+        assert!(show.next.is_some());
+        assert!(show.should_advance(1.0, 5.0));
+        show.advance(1.0);
+        assert_eq!(show.position, 2);
+        assert_eq!(show.wanted, 0);
+    }
+}
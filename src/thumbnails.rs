@@ -0,0 +1,51 @@
+use image::RgbaImage;
+use std::fs;
+use std::io;
+
+//Thumbnails are at most this many pixels on their longest side
+pub const THUMBNAIL_SIZE: u32 = 512;
+
+//Thumbnails live in the library's image directory as <image_directory>/thumbnails/<hash>.png
+pub fn thumbnail_dir(image_directory: &str) -> String {
+    format!("{}/thumbnails", image_directory)
+}
+
+fn thumbnail_path(dir: &str, hash: &str) -> String {
+    format!("{}/{}.png", dir, hash)
+}
+
+//Downscales the image to fit within THUMBNAIL_SIZE, keeping its aspect ratio
+pub fn make_thumbnail(pixels: &RgbaImage) -> RgbaImage {
+    let (width, height) = pixels.dimensions();
+    if width <= THUMBNAIL_SIZE && height <= THUMBNAIL_SIZE {
+        return pixels.clone();
+    }
+
+    let scale = THUMBNAIL_SIZE as f32 / u32::max(width, height) as f32;
+    let new_width = u32::max(1, (width as f32 * scale) as u32);
+    let new_height = u32::max(1, (height as f32 * scale) as u32);
+    image::imageops::thumbnail(pixels, new_width, new_height)
+}
+
+//The cached thumbnail for the image with this hash, if one has been generated
+pub fn load_cached(dir: &str, hash: &str) -> Option<RgbaImage> {
+    let path = thumbnail_path(dir, hash);
+    match image::open(&path) {
+        Ok(im) => { Some(im.to_rgba8()) }
+        Err(_) => { None }
+    }
+}
+
+pub fn save(dir: &str, hash: &str, thumbnail: &RgbaImage) -> Result<(), String> {
+    fs::create_dir_all(dir).map_err(|e| e.to_string())?;
+    thumbnail.save_with_format(thumbnail_path(dir, hash), image::ImageFormat::Png).map_err(|e| e.to_string())
+}
+
+//Deletes the cached thumbnail for the image with this hash. It not having been generated yet is fine
+pub fn remove(dir: &str, hash: &str) -> Result<(), String> {
+    match fs::remove_file(thumbnail_path(dir, hash)) {
+        Ok(_) => { Ok(()) }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => { Ok(()) }
+        Err(e) => { Err(e.to_string()) }
+    }
+}